@@ -0,0 +1,230 @@
+use crate::config::Config;
+
+pub const USAGE: &str = "Usage: ./flash <command> [options]
+
+Commands:
+  build    Hash the data set, insert it into a new index and save it to --index
+  query    Query the index saved at --index and write the results to --output
+  eval     Score a results file given by --results against the vectors stored in --index
+  sweep    Run build, query and eval once for each value given by --sweep
+  stats    Report bucket occupancy and memory use of the index saved at --index
+  plan     Estimate the memory build and query need and check it against memory_budget
+
+Options:
+  --config <file>            Load a config file before applying overrides
+  --index <file>             Index file written by build and read by query, eval and stats
+                             (default index.flash)
+  --output <file>            Results file written by query (default results.txt)
+  --results <file>           Results file read by eval (default results.txt)
+  --sweep <key>=<v1>,<v2>    Parameter and values for sweep, e.g. tables=16,32,64
+
+Overrides:
+  --tables, --k, --range-pow, --reservoir-size, --eviction, --seed, --probes, --storage,
+  --filename, --avg-dim, --num-data, --num-query,
+  --topk, --simk, --rerank, --candidates, --label, --threads, --memory-budget <value>
+                             Override the matching config field";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+  Build,
+  Query,
+  Eval,
+  Sweep,
+  Stats,
+  Plan,
+}
+
+pub struct Args {
+  pub command: Command,
+  pub config: Config,
+  pub index: String,
+  pub output: String,
+  pub results: String,
+  pub sweep: Option<(String, Vec<String>)>,
+}
+
+/// Maps an override flag, without its leading dashes, to the config key it sets.
+fn config_key(flag: &str) -> Option<&'static str> {
+  match flag {
+    "tables" => Some("lsh.tables"),
+    "k" => Some("lsh.k"),
+    "range-pow" => Some("lsh.range_pow"),
+    "reservoir-size" => Some("lsh.reservoir_size"),
+    "eviction" => Some("lsh.eviction"),
+    "seed" => Some("lsh.seed"),
+    "probes" => Some("lsh.probes"),
+    "storage" => Some("lsh.storage"),
+    "filename" => Some("data.filename"),
+    "avg-dim" => Some("data.avg_dim"),
+    "num-data" => Some("data.num_data"),
+    "num-query" => Some("data.num_query"),
+    "topk" => Some("topk"),
+    "simk" => Some("simk"),
+    "rerank" => Some("rerank"),
+    "candidates" => Some("candidates"),
+    "label" => Some("label"),
+    "threads" => Some("threads"),
+    "memory-budget" => Some("memory_budget"),
+    _ => None,
+  }
+}
+
+/// Parses the arguments following the program name. The config file is loaded first regardless
+/// of where `--config` appears so that overrides always take precedence over it.
+pub fn parse_args(args: &[String]) -> Result<Args, String> {
+  let command = match args.first().map(|s| s.as_str()) {
+    Some("build") => Command::Build,
+    Some("query") => Command::Query,
+    Some("eval") => Command::Eval,
+    Some("sweep") => Command::Sweep,
+    Some("stats") => Command::Stats,
+    Some("plan") => Command::Plan,
+    Some(x) => return Err(format!("unknown command '{}'", x)),
+    None => return Err(String::from("missing command")),
+  };
+
+  let mut pairs: Vec<(&str, &str)> = Vec::new();
+  let mut i = 1;
+  while i < args.len() {
+    let flag = args[i]
+      .strip_prefix("--")
+      .ok_or_else(|| format!("unexpected argument '{}'", args[i]))?;
+    let value = args
+      .get(i + 1)
+      .ok_or_else(|| format!("missing value for '--{}'", flag))?;
+    pairs.push((flag, value));
+    i += 2;
+  }
+
+  let mut config = match pairs.iter().rev().find(|(flag, _)| *flag == "config") {
+    Some((_, path)) => Config::from_file(path).map_err(|e| format!("invalid config: {}", e))?,
+    None => Config::default(),
+  };
+
+  let mut index = String::from("index.flash");
+  let mut output = String::from("results.txt");
+  let mut results = String::from("results.txt");
+  let mut sweep = None;
+
+  for (flag, value) in pairs {
+    match flag {
+      "config" => {}
+      "index" => index = value.to_string(),
+      "output" => output = value.to_string(),
+      "results" => results = value.to_string(),
+      "sweep" => sweep = Some(parse_sweep(value)?),
+      _ => {
+        let key = config_key(flag).ok_or_else(|| format!("unknown option '--{}'", flag))?;
+        config.set_from_str(key, value)?;
+      }
+    }
+  }
+
+  match &sweep {
+    Some((key, values)) => {
+      for v in values {
+        config.clone().set_from_str(key, v)?;
+      }
+    }
+    None if command == Command::Sweep => {
+      return Err(String::from("sweep requires '--sweep <key>=<values>'"))
+    }
+    None => {}
+  }
+
+  Ok(Args {
+    command,
+    config,
+    index,
+    output,
+    results,
+    sweep,
+  })
+}
+
+fn parse_sweep(spec: &str) -> Result<(String, Vec<String>), String> {
+  let eq = spec
+    .find('=')
+    .ok_or_else(|| format!("sweep '{}' should have the form <key>=<v1>,<v2>", spec))?;
+
+  let flag = &spec[..eq];
+  let key = config_key(flag)
+    .or_else(|| Config::KEYS.iter().find(|&&k| k == flag).copied())
+    .ok_or_else(|| format!("unknown sweep parameter '{}'", flag))?;
+
+  let values: Vec<String> = spec[eq + 1..]
+    .split(',')
+    .filter(|v| !v.is_empty())
+    .map(|v| v.to_string())
+    .collect();
+  if values.is_empty() {
+    return Err(format!("sweep '{}' has no values", spec));
+  }
+
+  Ok((key.to_string(), values))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lsh::EvictionPolicy;
+
+  fn to_args(s: &str) -> Vec<String> {
+    s.split(' ').map(|x| x.to_string()).collect()
+  }
+
+  #[test]
+  fn test_overrides() {
+    let args = parse_args(&to_args(
+      "query --tables 64 --seed 5 --eviction oldest --range-pow 12 --filename data.svm --topk 5 --output out.txt --index a.idx",
+    ))
+    .expect("args should parse");
+
+    assert_eq!(args.command, Command::Query);
+    assert_eq!(args.config.lsh.tables, 64);
+    assert_eq!(args.config.lsh.range_pow, 12);
+    assert_eq!(args.config.lsh.seed, Some(5));
+    assert_eq!(args.config.lsh.eviction, EvictionPolicy::KeepOldest);
+    assert_eq!(args.config.lsh.k, Config::default().lsh.k);
+    assert_eq!(args.config.data.filename, "data.svm");
+    assert_eq!(args.config.topk, 5);
+    assert_eq!(args.output, "out.txt");
+    assert_eq!(args.index, "a.idx");
+    assert!(args.sweep.is_none());
+  }
+
+  #[test]
+  fn test_sweep() {
+    let args = parse_args(&to_args("sweep --sweep reservoir-size=8,16,32")).unwrap();
+
+    assert_eq!(args.command, Command::Sweep);
+    let (key, values) = args.sweep.unwrap();
+    assert_eq!(key, "lsh.reservoir_size");
+    assert_eq!(values, vec!["8", "16", "32"]);
+
+    assert!(parse_args(&to_args("sweep")).is_err());
+    assert!(parse_args(&to_args("sweep --sweep tables=8,x")).is_err());
+    assert!(parse_args(&to_args("sweep --sweep width=8")).is_err());
+  }
+
+  #[test]
+  fn test_stats_and_plan() {
+    let args = parse_args(&to_args("stats --index a.idx")).unwrap();
+    assert_eq!(args.command, Command::Stats);
+    assert_eq!(args.index, "a.idx");
+
+    let args = parse_args(&to_args("plan --range-pow 20 --memory-budget 2GB")).unwrap();
+    assert_eq!(args.command, Command::Plan);
+    assert_eq!(args.config.memory_budget, Some(2 << 30));
+  }
+
+  #[test]
+  fn test_invalid_args() {
+    assert!(parse_args(&[]).is_err());
+    assert!(parse_args(&to_args("index")).is_err());
+    assert!(parse_args(&to_args("build --tables")).is_err());
+    assert!(parse_args(&to_args("build --tables four")).is_err());
+    assert!(parse_args(&to_args("build --width 4")).is_err());
+    assert!(parse_args(&to_args("build tables 4")).is_err());
+  }
+}
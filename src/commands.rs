@@ -0,0 +1,406 @@
+use crate::config::Config;
+use crate::doph::DOPH;
+use crate::evaluate::average_cosine_similarity;
+use crate::index::Index;
+use crate::labels::Labels;
+use crate::lsh::{HashType, IDType, QueryResult, Storage, LSH};
+use crate::plan::{suggest, MemoryPlan};
+use crate::reader::{read_data_svm, read_data_svm_labeled, SVMData};
+use crate::stats::{format_bytes, Stats};
+use crate::store::VectorStore;
+
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use std::time::Instant;
+
+fn build_index(config: &Config) -> Index {
+  println!(
+    "Reading {} vectors as insertion dataset",
+    config.data.num_data
+  );
+
+  let (data, labels) = read_data_svm_labeled(
+    &config.data.filename,
+    config.data.num_data,
+    config.data.avg_dim,
+    config.data.num_query,
+  );
+
+  println!("\t-Done");
+
+  let mut rng = match config.lsh.seed {
+    Some(seed) => StdRng::seed_from_u64(seed),
+    None => StdRng::from_entropy(),
+  };
+
+  let doph = DOPH::with_rng(
+    config.lsh.tables,
+    config.lsh.k,
+    config.lsh.range_pow as HashType,
+    &mut rng,
+  );
+
+  // A frozen index is built with sparse storage and compressed once every item is in.
+  let storage = match config.lsh.storage {
+    Storage::Frozen => Storage::Sparse,
+    storage => storage,
+  };
+  let mut lsh = LSH::with_storage(
+    config.lsh.tables,
+    config.lsh.range_pow,
+    config.lsh.reservoir_size,
+    storage,
+    &mut rng,
+  )
+  .expect("Frozen storage is built as sparse above")
+  .with_eviction(config.lsh.eviction);
+
+  println!("Hashing data");
+
+  let hashes = doph.hash(&data);
+
+  println!("\t-Done");
+
+  println!("Inserting data");
+
+  lsh.insert_range_parallel(
+    config.data.num_query as IDType,
+    config.data.num_data,
+    &hashes,
+    config.threads,
+  );
+
+  println!("\t-Done");
+
+  if config.lsh.storage == Storage::Frozen {
+    println!("Freezing index");
+    lsh.freeze();
+    println!("\t-Done");
+  }
+
+  let mut store = VectorStore::new();
+  store.insert_range(config.data.num_query as IDType, &data);
+  let mut item_labels = Labels::new();
+  item_labels.insert_range(config.data.num_query as IDType, &labels);
+
+  return Index::new(doph, lsh)
+    .with_store(store)
+    .with_labels(item_labels);
+}
+
+fn read_queries(config: &Config) -> SVMData {
+  println!("Reading {} vectors as query dataset", config.data.num_query);
+
+  let queries = read_data_svm(
+    &config.data.filename,
+    config.data.num_query,
+    config.data.avg_dim,
+    0,
+  );
+
+  println!("\t-Done");
+
+  return queries;
+}
+
+fn query_index(
+  config: &Config,
+  index: &Index,
+  queries: &SVMData,
+) -> Result<QueryResult<f32>, String> {
+  println!("Hashing and querying data");
+
+  let k = match config.rerank {
+    Some(_) => config.candidates,
+    None => config.topk,
+  };
+  let results = match &config.label {
+    Some(label) => {
+      if index.labels.is_empty() {
+        return Err(String::from(
+          "the index has no labels to filter by, rebuild it to store them",
+        ));
+      }
+      index.query_with_label(queries, k, config.lsh.probes, label, config.threads)
+    }
+    None => index.query_multiprobe(queries, k, config.lsh.probes, config.threads),
+  };
+
+  println!("\t-Done");
+
+  let metric = match config.rerank {
+    Some(metric) => metric,
+    None => return Ok(results.map_scores(|count| count as f32)),
+  };
+
+  if index.store.is_empty() {
+    return Err(String::from(
+      "the index has no stored vectors to re-rank with, rebuild it to store them",
+    ));
+  }
+
+  println!("Re-ranking by {} similarity", metric.name());
+
+  let results = index.rerank(&results, queries, config.topk, metric, config.threads);
+
+  println!("\t-Done");
+
+  return Ok(results);
+}
+
+fn evaluate_results(
+  config: &Config,
+  results: &QueryResult<f32>,
+  queries: &SVMData,
+  store: &VectorStore,
+) -> f32 {
+  println!("Computing average cosine similarity");
+
+  if !store.is_empty() {
+    return average_cosine_similarity(
+      results,
+      queries,
+      store.data(),
+      |id| store.row(id),
+      config.simk,
+    );
+  }
+
+  println!(
+    "Reading all {} vectors for evaluation",
+    config.data.num_data + config.data.num_query
+  );
+
+  let all_data = read_data_svm(
+    &config.data.filename,
+    config.data.num_data + config.data.num_query,
+    config.data.avg_dim,
+    0,
+  );
+
+  println!("\t-Done");
+
+  return average_cosine_similarity(
+    results,
+    queries,
+    &all_data,
+    |id| Some(id as usize),
+    config.simk,
+  );
+}
+
+/// Fails if building and querying with `config` would take more than its `memory_budget`.
+fn check_budget(config: &Config) -> Result<(), String> {
+  let budget = match config.memory_budget {
+    Some(budget) => budget,
+    None => return Ok(()),
+  };
+  let total = MemoryPlan::of(config).total();
+  if total > budget {
+    return Err(format!(
+      "the index would need about {} but memory_budget is {}, run plan for parameters that fit",
+      format_bytes(total),
+      format_bytes(budget)
+    ));
+  }
+  Ok(())
+}
+
+pub fn build(config: &Config, index: &str) -> Result<(), String> {
+  check_budget(config)?;
+
+  let start = Instant::now();
+
+  let built = build_index(config);
+
+  println!("Built index in {:.2?}", start.elapsed());
+
+  println!("Saving index to {}", index);
+
+  built
+    .save(index)
+    .map_err(|e| format!("unable to save index to '{}': {}", index, e))?;
+
+  println!("\t-Done");
+
+  Ok(())
+}
+
+pub fn query(config: &Config, index: &str, output: &str) -> Result<(), String> {
+  check_budget(config)?;
+
+  println!("Loading index from {}", index);
+
+  let loaded =
+    Index::load(index).map_err(|e| format!("unable to load index from '{}': {}", index, e))?;
+
+  println!("\t-Done");
+
+  let queries = read_queries(config);
+  let results = query_index(config, &loaded, &queries)?;
+
+  results
+    .save(output)
+    .map_err(|e| format!("unable to write results to '{}': {}", output, e))?;
+
+  println!("Wrote results for {} queries to {}", results.len(), output);
+
+  Ok(())
+}
+
+pub fn eval(config: &Config, index: &str, results_file: &str) -> Result<(), String> {
+  let results = QueryResult::<f32>::load(results_file)
+    .map_err(|e| format!("unable to read results from '{}': {}", results_file, e))?;
+
+  if results.len() < config.data.num_query {
+    return Err(format!(
+      "results file '{}' has {} queries but num_query is {}",
+      results_file,
+      results.len(),
+      config.data.num_query
+    ));
+  }
+
+  println!("Loading stored vectors from {}", index);
+
+  // Without an index the vectors are read from the dataset instead.
+  let store = match Index::load(index) {
+    Ok(loaded) => {
+      println!("\t-Done");
+      loaded.store
+    }
+    Err(e) => {
+      println!("\t-Unable to load index from '{}': {}", index, e);
+      VectorStore::new()
+    }
+  };
+
+  let queries = read_queries(config);
+  let sim = evaluate_results(config, &results, &queries, &store);
+
+  println!("Average cosine similarity @{} is {}", config.simk, sim);
+
+  Ok(())
+}
+
+pub fn stats(index: &str) -> Result<(), String> {
+  println!("Loading index from {}", index);
+
+  let loaded =
+    Index::load(index).map_err(|e| format!("unable to load index from '{}': {}", index, e))?;
+
+  println!("\t-Done");
+
+  let stats = Stats::of(&loaded.lsh);
+  let store_bytes = loaded.store.memory_bytes();
+
+  println!(
+    "{} storage, {} stored vectors taking {}, {} in total",
+    loaded.lsh.storage().name(),
+    loaded.store.len(),
+    format_bytes(store_bytes),
+    format_bytes(stats.memory_bytes + store_bytes)
+  );
+  print!("{}", stats);
+
+  Ok(())
+}
+
+pub fn plan(config: &Config) -> Result<(), String> {
+  let plan = MemoryPlan::of(config);
+  println!(
+    "Memory needed for {} vectors and {} queries with {} storage:",
+    config.data.num_data,
+    config.data.num_query,
+    config.lsh.storage.name()
+  );
+  println!("{}", plan);
+
+  let budget = match config.memory_budget {
+    Some(budget) => budget,
+    None => return Ok(()),
+  };
+  if plan.total() <= budget {
+    println!("Fits in the memory budget of {}", format_bytes(budget));
+    return Ok(());
+  }
+
+  let suggestions = suggest(config, budget);
+  if suggestions.is_empty() {
+    return Err(format!(
+      "the plan needs {} but memory_budget is {}, and no smaller range_pow, reservoir_size \
+       or tables fits",
+      format_bytes(plan.total()),
+      format_bytes(budget)
+    ));
+  }
+  println!();
+  println!("Any one of these changes fits in {}:", format_bytes(budget));
+  for s in &suggestions {
+    println!(
+      "  {:<24} total {}",
+      format!("{} = {}", s.key, s.value),
+      format_bytes(s.plan.total())
+    );
+  }
+  return Err(format!(
+    "the plan needs {} but memory_budget is {}",
+    format_bytes(plan.total()),
+    format_bytes(budget)
+  ));
+}
+
+pub fn sweep(config: &Config, key: &str, values: &[String]) -> Result<(), String> {
+  // Check every run up front so a later one cannot fail after the earlier ones have finished.
+  for value in values {
+    let mut run = config.clone();
+    run.set_from_str(key, value)?;
+    run
+      .validate()
+      .map_err(|e| format!("with {} = {}, {}", key, value, e))?;
+    check_budget(&run).map_err(|e| format!("with {} = {}, {}", key, value, e))?;
+  }
+
+  let mut rows = Vec::with_capacity(values.len());
+
+  for value in values {
+    let mut run = config.clone();
+    run.set_from_str(key, value)?;
+
+    println!("=== {} = {} ===", key, value);
+
+    let start = Instant::now();
+    let index = build_index(&run);
+    let build_time = start.elapsed();
+
+    let queries = read_queries(&run);
+
+    let start = Instant::now();
+    let results = query_index(&run, &index, &queries)?;
+    let query_time = start.elapsed();
+
+    let sim = evaluate_results(&run, &results, &queries, &index.store);
+
+    rows.push((value, build_time, query_time, sim));
+  }
+
+  println!();
+  println!(
+    "{:>12} {:>12} {:>12} {:>12}",
+    key,
+    "build",
+    "query",
+    format!("sim@{}", config.simk)
+  );
+  for (value, build_time, query_time, sim) in rows {
+    println!(
+      "{:>12} {:>12} {:>12} {:>12.4}",
+      value,
+      format!("{:.2?}", build_time),
+      format!("{:.2?}", query_time),
+      sim
+    );
+  }
+
+  Ok(())
+}
@@ -1,20 +1,887 @@
+use crate::doph::max_probes;
+use crate::evaluate::Similarity;
+use crate::lsh::{EvictionPolicy, IDType, Storage};
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+/// The values accepted by the `rerank` key.
+const RERANK_NAMES: &str = "\"none\", \"cosine\" or \"jaccard\"";
+
+/// The values accepted by the `memory_budget` key.
+const BUDGET_NAMES: &str = "a number of bytes or a size such as \"512MB\" or \"8GB\"";
+
+#[derive(Clone)]
 pub struct LSHConfig {
   pub tables: usize,
   pub k: usize,
   pub range_pow: usize,
   pub reservoir_size: usize,
+  /// Decides which items a full bucket keeps.
+  pub eviction: EvictionPolicy,
+  /// Seeds the hash functions and reservoir sampling. Without a seed every run draws new ones.
+  pub seed: Option<u64>,
+  /// Extra buckets visited in each table when querying.
+  pub probes: usize,
+  /// Whether every bucket is preallocated or only the occupied ones are stored.
+  pub storage: Storage,
+}
+
+impl Default for LSHConfig {
+  fn default() -> Self {
+    LSHConfig {
+      tables: 32,
+      k: 4,
+      range_pow: 15,
+      reservoir_size: 32,
+      eviction: EvictionPolicy::Reservoir,
+      seed: None,
+      probes: 0,
+      storage: Storage::Dense,
+    }
+  }
 }
 
+#[derive(Clone)]
 pub struct DataConfig {
-  pub filename: &'static str,
+  pub filename: String,
   pub avg_dim: usize,
   pub num_data: usize,
   pub num_query: usize,
 }
 
+impl Default for DataConfig {
+  fn default() -> Self {
+    DataConfig {
+      filename: String::new(),
+      avg_dim: 32,
+      num_data: 0,
+      num_query: 0,
+    }
+  }
+}
+
+#[derive(Clone)]
 pub struct Config {
   pub lsh: LSHConfig,
   pub data: DataConfig,
   pub topk: usize,
   pub simk: usize,
+  /// Re-ranks `candidates` results per query by exact similarity before keeping the top `topk`.
+  pub rerank: Option<Similarity>,
+  pub candidates: usize,
+  /// Only returns results whose label, the first field of their line in the data file, is this.
+  pub label: Option<String>,
+  /// Worker threads used to build and query the index.
+  pub threads: usize,
+  /// The most memory, in bytes, that building and querying may take, see `MemoryPlan`.
+  pub memory_budget: Option<usize>,
+}
+
+impl Default for Config {
+  fn default() -> Self {
+    Config {
+      lsh: LSHConfig::default(),
+      data: DataConfig::default(),
+      topk: 10,
+      simk: 10,
+      rerank: None,
+      candidates: 100,
+      label: None,
+      threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+      memory_budget: None,
+    }
+  }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Value {
+  Integer(usize),
+  String(String),
+}
+
+impl Value {
+  fn type_name(&self) -> &'static str {
+    match self {
+      Value::Integer(_) => "integer",
+      Value::String(_) => "string",
+    }
+  }
+}
+
+impl fmt::Display for Value {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Value::Integer(x) => write!(f, "{}", x),
+      Value::String(s) => write!(f, "\"{}\"", s),
+    }
+  }
+}
+
+#[derive(Debug)]
+pub enum KeyError {
+  Unknown,
+  WrongType { expected: &'static str },
+  InvalidValue { expected: &'static str },
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+  Io {
+    path: String,
+    err: io::Error,
+  },
+  Syntax {
+    line: usize,
+    msg: String,
+  },
+  UnknownKey {
+    line: usize,
+    key: String,
+  },
+  DuplicateKey {
+    line: usize,
+    key: String,
+  },
+  WrongType {
+    line: usize,
+    key: String,
+    expected: &'static str,
+    found: Value,
+  },
+  InvalidValue {
+    line: usize,
+    key: String,
+    expected: &'static str,
+    found: Value,
+  },
+  Invalid(Vec<String>),
+}
+
+impl fmt::Display for ConfigError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      ConfigError::Io { path, err } => write!(f, "unable to read config '{}': {}", path, err),
+      ConfigError::Syntax { line, msg } => write!(f, "line {}: {}", line, msg),
+      ConfigError::UnknownKey { line, key } => write!(f, "line {}: unknown key '{}'", line, key),
+      ConfigError::DuplicateKey { line, key } => {
+        write!(f, "line {}: key '{}' is set more than once", line, key)
+      }
+      ConfigError::WrongType {
+        line,
+        key,
+        expected,
+        found,
+      } => write!(
+        f,
+        "line {}: key '{}' expects a value of type {} but found {} {}",
+        line,
+        key,
+        expected,
+        found.type_name(),
+        found
+      ),
+      ConfigError::InvalidValue {
+        line,
+        key,
+        expected,
+        found,
+      } => write!(
+        f,
+        "line {}: key '{}' expects one of {} but found {}",
+        line, key, expected, found
+      ),
+      ConfigError::Invalid(violations) => {
+        write!(f, "{} invalid parameter(s)", violations.len())?;
+        for v in violations {
+          write!(f, "\n  - {}", v)?;
+        }
+        Ok(())
+      }
+    }
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+  /// Every key accepted by `set`, in the dotted form used by config files.
+  pub const KEYS: [&'static str; 19] = [
+    "lsh.tables",
+    "lsh.k",
+    "lsh.range_pow",
+    "lsh.reservoir_size",
+    "lsh.eviction",
+    "lsh.seed",
+    "lsh.probes",
+    "lsh.storage",
+    "data.filename",
+    "data.avg_dim",
+    "data.num_data",
+    "data.num_query",
+    "topk",
+    "simk",
+    "rerank",
+    "candidates",
+    "label",
+    "threads",
+    "memory_budget",
+  ];
+
+  pub fn from_file(path: &str) -> Result<Config, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|err| ConfigError::Io {
+      path: path.to_string(),
+      err,
+    })?;
+    Config::parse(&text)
+  }
+
+  /// Parses a TOML style config. Top level keys and `[lsh]`/`[data]` sections of `key = value`
+  /// pairs are supported, where values are integers or double quoted strings. Any key that is
+  /// omitted keeps its default value.
+  pub fn parse(text: &str) -> Result<Config, ConfigError> {
+    let mut config = Config::default();
+    let mut seen: Vec<String> = Vec::new();
+    let mut section = String::new();
+
+    for (i, raw) in text.lines().enumerate() {
+      let line = i + 1;
+      let s = strip_comment(raw).trim();
+      if s.is_empty() {
+        continue;
+      }
+
+      if s.starts_with('[') {
+        if !s.ends_with(']') {
+          return Err(ConfigError::Syntax {
+            line,
+            msg: format!("unterminated section header '{}'", s),
+          });
+        }
+        section = s[1..s.len() - 1].trim().to_string();
+        if section != "lsh" && section != "data" {
+          return Err(ConfigError::Syntax {
+            line,
+            msg: format!("unknown section '[{}]'", section),
+          });
+        }
+        continue;
+      }
+
+      let eq = s.find('=').ok_or_else(|| ConfigError::Syntax {
+        line,
+        msg: format!("expected 'key = value' but found '{}'", s),
+      })?;
+
+      let name = s[..eq].trim();
+      if name.is_empty() {
+        return Err(ConfigError::Syntax {
+          line,
+          msg: String::from("missing key before '='"),
+        });
+      }
+      let key = if section.is_empty() {
+        name.to_string()
+      } else {
+        format!("{}.{}", section, name)
+      };
+
+      let value =
+        parse_value(s[eq + 1..].trim()).map_err(|msg| ConfigError::Syntax { line, msg })?;
+
+      if seen.contains(&key) {
+        return Err(ConfigError::DuplicateKey { line, key });
+      }
+
+      match config.set(&key, value) {
+        Ok(()) => seen.push(key),
+        Err((KeyError::Unknown, _)) => return Err(ConfigError::UnknownKey { line, key }),
+        Err((KeyError::WrongType { expected }, found)) => {
+          return Err(ConfigError::WrongType {
+            line,
+            key,
+            expected,
+            found,
+          })
+        }
+        Err((KeyError::InvalidValue { expected }, found)) => {
+          return Err(ConfigError::InvalidValue {
+            line,
+            key,
+            expected,
+            found,
+          })
+        }
+      }
+    }
+
+    Ok(config)
+  }
+
+  /// Checks every constraint the hashing and indexing code relies on and reports all of the
+  /// violated ones at once, so that a bad config fails before any data is read or allocated.
+  pub fn validate(&self) -> Result<(), ConfigError> {
+    let mut violations = Vec::new();
+    let lsh = &self.lsh;
+
+    if lsh.tables == 0 {
+      violations.push(String::from("lsh.tables must be at least 1"));
+    }
+    if lsh.k == 0 {
+      violations.push(String::from("lsh.k must be at least 1"));
+    }
+    if lsh.range_pow == 0 || lsh.range_pow >= 32 {
+      violations.push(format!(
+        "lsh.range_pow must be between 1 and 31 since hashes are 32 bits, but is {}",
+        lsh.range_pow
+      ));
+    } else if lsh.k.saturating_mul(lsh.tables) > 1 << lsh.range_pow {
+      violations.push(format!(
+        "lsh.k * lsh.tables ({} * {} = {}) must not exceed 2^lsh.range_pow (2^{} = {}), \
+         otherwise the min-hash bins are empty",
+        lsh.k,
+        lsh.tables,
+        lsh.k.saturating_mul(lsh.tables),
+        lsh.range_pow,
+        1usize << lsh.range_pow
+      ));
+    }
+    if lsh.reservoir_size == 0 {
+      violations.push(String::from("lsh.reservoir_size must be at least 1"));
+    } else if lsh.reservoir_size >= IDType::MAX as usize {
+      violations.push(format!(
+        "lsh.reservoir_size must be less than {}, but is {}",
+        IDType::MAX,
+        lsh.reservoir_size
+      ));
+    }
+    if lsh.k > 0 && lsh.probes > max_probes(lsh.k) {
+      violations.push(format!(
+        "lsh.probes must be at most 2^lsh.k - 1 ({}), the number of distinct buckets a table can \
+         be probed in, but is {}",
+        max_probes(lsh.k),
+        lsh.probes
+      ));
+    } else if lsh.range_pow < 32 && lsh.probes >= 1 << lsh.range_pow {
+      violations.push(format!(
+        "lsh.probes must be less than the number of buckets per table (2^{}), but is {}",
+        lsh.range_pow, lsh.probes
+      ));
+    }
+    if lsh.range_pow < 32 {
+      let size = lsh
+        .tables
+        .checked_mul(1 << lsh.range_pow)
+        .and_then(|x| x.checked_mul(lsh.reservoir_size.saturating_add(1)))
+        .and_then(|x| x.checked_mul(std::mem::size_of::<IDType>()));
+      if size.is_none() {
+        violations.push(format!(
+          "index size in bytes lsh.tables * 2^lsh.range_pow * (lsh.reservoir_size + 1) * {} \
+           ({} * 2^{} * {} * {}) overflows",
+          std::mem::size_of::<IDType>(),
+          lsh.tables,
+          lsh.range_pow,
+          lsh.reservoir_size.saturating_add(1),
+          std::mem::size_of::<IDType>()
+        ));
+      }
+    }
+
+    if self.data.filename.is_empty() {
+      violations.push(String::from("data.filename must be set"));
+    }
+    if self.data.num_data == 0 {
+      violations.push(String::from("data.num_data must be at least 1"));
+    }
+    if self.data.num_data.saturating_add(self.data.num_query) > IDType::MAX as usize {
+      violations.push(format!(
+        "data.num_data + data.num_query ({} + {}) must fit in a {} bit id",
+        self.data.num_data,
+        self.data.num_query,
+        std::mem::size_of::<IDType>() * 8
+      ));
+    }
+
+    if self.topk == 0 {
+      violations.push(String::from("topk must be at least 1"));
+    }
+    if self.simk == 0 || self.simk > self.topk {
+      violations.push(format!(
+        "simk must be between 1 and topk ({}), but is {}",
+        self.topk, self.simk
+      ));
+    }
+
+    if self.rerank.is_some() && self.candidates < self.topk {
+      violations.push(format!(
+        "candidates must be at least topk ({}) when re-ranking, but is {}",
+        self.topk, self.candidates
+      ));
+    }
+
+    if self.threads == 0 {
+      violations.push(String::from("threads must be at least 1"));
+    }
+
+    if violations.is_empty() {
+      Ok(())
+    } else {
+      Err(ConfigError::Invalid(violations))
+    }
+  }
+
+  /// Sets the field named by a dotted key such as `lsh.tables`. On failure the value is handed
+  /// back so the caller can report it.
+  pub fn set(&mut self, key: &str, value: Value) -> Result<(), (KeyError, Value)> {
+    let field: &mut usize = match key {
+      "lsh.tables" => &mut self.lsh.tables,
+      "lsh.k" => &mut self.lsh.k,
+      "lsh.range_pow" => &mut self.lsh.range_pow,
+      "lsh.reservoir_size" => &mut self.lsh.reservoir_size,
+      "lsh.probes" => &mut self.lsh.probes,
+      "data.avg_dim" => &mut self.data.avg_dim,
+      "data.num_data" => &mut self.data.num_data,
+      "data.num_query" => &mut self.data.num_query,
+      "topk" => &mut self.topk,
+      "simk" => &mut self.simk,
+      "candidates" => &mut self.candidates,
+      "threads" => &mut self.threads,
+      "lsh.seed" => {
+        return match value {
+          Value::Integer(x) => {
+            self.lsh.seed = Some(x as u64);
+            Ok(())
+          }
+          v => Err((
+            KeyError::WrongType {
+              expected: "integer",
+            },
+            v,
+          )),
+        };
+      }
+      "lsh.eviction" => {
+        return match value {
+          Value::String(s) => match s.parse() {
+            Ok(policy) => {
+              self.lsh.eviction = policy;
+              Ok(())
+            }
+            Err(_) => Err((
+              KeyError::InvalidValue {
+                expected: EvictionPolicy::NAMES,
+              },
+              Value::String(s),
+            )),
+          },
+          v => Err((KeyError::WrongType { expected: "string" }, v)),
+        };
+      }
+      "lsh.storage" => {
+        return match value {
+          Value::String(s) => match s.parse() {
+            Ok(storage) => {
+              self.lsh.storage = storage;
+              Ok(())
+            }
+            Err(_) => Err((
+              KeyError::InvalidValue {
+                expected: Storage::NAMES,
+              },
+              Value::String(s),
+            )),
+          },
+          v => Err((KeyError::WrongType { expected: "string" }, v)),
+        };
+      }
+      "rerank" => {
+        return match value {
+          Value::String(s) if s == "none" => {
+            self.rerank = None;
+            Ok(())
+          }
+          Value::String(s) => match s.parse() {
+            Ok(metric) => {
+              self.rerank = Some(metric);
+              Ok(())
+            }
+            Err(_) => Err((
+              KeyError::InvalidValue {
+                expected: RERANK_NAMES,
+              },
+              Value::String(s),
+            )),
+          },
+          v => Err((KeyError::WrongType { expected: "string" }, v)),
+        };
+      }
+      "label" => {
+        return match value {
+          Value::String(s) => {
+            self.label = Some(s);
+            Ok(())
+          }
+          v => Err((KeyError::WrongType { expected: "string" }, v)),
+        };
+      }
+      "memory_budget" => {
+        return match value {
+          Value::Integer(x) => {
+            self.memory_budget = Some(x);
+            Ok(())
+          }
+          Value::String(s) => match parse_bytes(&s) {
+            Some(bytes) => {
+              self.memory_budget = Some(bytes);
+              Ok(())
+            }
+            None => Err((
+              KeyError::InvalidValue {
+                expected: BUDGET_NAMES,
+              },
+              Value::String(s),
+            )),
+          },
+        };
+      }
+      "data.filename" => {
+        return match value {
+          Value::String(s) => {
+            self.data.filename = s;
+            Ok(())
+          }
+          v => Err((KeyError::WrongType { expected: "string" }, v)),
+        };
+      }
+      _ => return Err((KeyError::Unknown, value)),
+    };
+
+    match value {
+      Value::Integer(x) => {
+        *field = x;
+        Ok(())
+      }
+      v => Err((
+        KeyError::WrongType {
+          expected: "integer",
+        },
+        v,
+      )),
+    }
+  }
+
+  /// Sets a field from an unquoted string, such as a command line argument, by interpreting it
+  /// as whichever type the key expects.
+  pub fn set_from_str(&mut self, key: &str, raw: &str) -> Result<(), String> {
+    let value = match raw.replace('_', "").parse::<usize>() {
+      Ok(x) => Value::Integer(x),
+      Err(_) => Value::String(raw.to_string()),
+    };
+
+    let result = match self.set(key, value) {
+      Err((KeyError::WrongType { expected: "string" }, _)) => {
+        self.set(key, Value::String(raw.to_string()))
+      }
+      r => r,
+    };
+
+    result.map_err(|e| match e {
+      (KeyError::Unknown, _) => format!("unknown key '{}'", key),
+      (KeyError::WrongType { expected }, _) => {
+        format!(
+          "'{}' expects a value of type {} but found '{}'",
+          key, expected, raw
+        )
+      }
+      (KeyError::InvalidValue { expected }, _) => {
+        format!("'{}' expects one of {} but found '{}'", key, expected, raw)
+      }
+    })
+  }
+}
+
+fn strip_comment(line: &str) -> &str {
+  let mut in_string = false;
+  for (i, c) in line.char_indices() {
+    match c {
+      '"' => in_string = !in_string,
+      '#' if !in_string => return &line[..i],
+      _ => {}
+    }
+  }
+  line
+}
+
+/// Parses a size such as `8GB` or `1.5 TB`, where a KB is 1024 bytes.
+fn parse_bytes(s: &str) -> Option<usize> {
+  let s = s.trim();
+  let split = s
+    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+    .unwrap_or(s.len());
+  let number: f64 = s[..split].parse().ok()?;
+  let shift = match s[split..].trim().to_ascii_uppercase().as_str() {
+    "" | "B" => 0,
+    "KB" | "K" => 10,
+    "MB" | "M" => 20,
+    "GB" | "G" => 30,
+    "TB" | "T" => 40,
+    _ => return None,
+  };
+  return Some((number * (1u64 << shift) as f64) as usize);
+}
+
+fn parse_value(s: &str) -> Result<Value, String> {
+  if s.is_empty() {
+    return Err(String::from("missing value after '='"));
+  }
+
+  if let Some(rest) = s.strip_prefix('"') {
+    return match rest.strip_suffix('"') {
+      Some(inner) if !inner.contains('"') => Ok(Value::String(inner.to_string())),
+      _ => Err(format!("malformed string value {}", s)),
+    };
+  }
+
+  s.replace('_', "")
+    .parse::<usize>()
+    .map(Value::Integer)
+    .map_err(|_| format!("'{}' is not a non-negative integer or quoted string", s))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_full_config() {
+    let text = r#"
+      # Top level
+      topk = 100
+      simk = 10
+      threads = 3
+
+      [lsh]
+      tables = 64
+      k = 4      # hashes per table
+      range_pow = 18
+      reservoir_size = 1_000
+      eviction = "newest"
+      seed = 7
+      probes = 2
+      storage = "sparse"
+
+      [data]
+      filename = "/data/webspam#1.svm"
+      avg_dim = 4000
+      num_data = 340000
+      num_query = 10000
+    "#;
+
+    let config = Config::parse(text).expect("config should parse");
+
+    assert_eq!(config.topk, 100);
+    assert_eq!(config.simk, 10);
+    assert_eq!(config.threads, 3);
+    assert_eq!(config.lsh.tables, 64);
+    assert_eq!(config.lsh.k, 4);
+    assert_eq!(config.lsh.range_pow, 18);
+    assert_eq!(config.lsh.reservoir_size, 1000);
+    assert_eq!(config.lsh.seed, Some(7));
+    assert_eq!(config.lsh.eviction, EvictionPolicy::KeepNewest);
+    assert_eq!(config.lsh.probes, 2);
+    assert_eq!(config.lsh.storage, Storage::Sparse);
+    assert_eq!(config.data.filename, "/data/webspam#1.svm");
+    assert_eq!(config.data.avg_dim, 4000);
+    assert_eq!(config.data.num_data, 340000);
+    assert_eq!(config.data.num_query, 10000);
+  }
+
+  #[test]
+  fn test_defaults_and_dotted_keys() {
+    let config = Config::parse("lsh.tables = 8\ndata.filename = \"x.svm\"").unwrap();
+    let default = Config::default();
+
+    assert_eq!(config.lsh.tables, 8);
+    assert_eq!(config.data.filename, "x.svm");
+    assert_eq!(config.lsh.k, default.lsh.k);
+    assert_eq!(config.lsh.range_pow, default.lsh.range_pow);
+    assert_eq!(config.lsh.seed, None);
+    assert_eq!(config.data.avg_dim, default.data.avg_dim);
+    assert_eq!(config.topk, default.topk);
+  }
+
+  #[test]
+  fn test_errors() {
+    match Config::parse("[lsh]\ntable = 4") {
+      Err(ConfigError::UnknownKey { line, key }) => {
+        assert_eq!(line, 2);
+        assert_eq!(key, "lsh.table");
+      }
+      _ => panic!("expected unknown key error"),
+    }
+
+    match Config::parse("[data]\nnum_data = \"10\"") {
+      Err(ConfigError::WrongType { key, expected, .. }) => {
+        assert_eq!(key, "data.num_data");
+        assert_eq!(expected, "integer");
+      }
+      _ => panic!("expected type error"),
+    }
+
+    match Config::parse("[data]\nfilename = 7") {
+      Err(ConfigError::WrongType {
+        expected, found, ..
+      }) => {
+        assert_eq!(expected, "string");
+        assert_eq!(found, Value::Integer(7));
+      }
+      _ => panic!("expected type error"),
+    }
+
+    match Config::parse("[lsh]\neviction = \"fifo\"") {
+      Err(ConfigError::InvalidValue { key, found, .. }) => {
+        assert_eq!(key, "lsh.eviction");
+        assert_eq!(found, Value::String(String::from("fifo")));
+      }
+      _ => panic!("expected invalid value error"),
+    }
+
+    assert!(matches!(
+      Config::parse("topk = -3"),
+      Err(ConfigError::Syntax { line: 1, .. })
+    ));
+    assert!(matches!(
+      Config::parse("[index]"),
+      Err(ConfigError::Syntax { .. })
+    ));
+    assert!(matches!(
+      Config::parse("topk = 1\ntopk = 2"),
+      Err(ConfigError::DuplicateKey { line: 2, .. })
+    ));
+  }
+
+  #[test]
+  fn test_set_from_str() {
+    let mut config = Config::default();
+
+    config.set_from_str("lsh.tables", "128").unwrap();
+    config.set_from_str("data.filename", "12").unwrap();
+    config.set_from_str("data.num_data", "1_000").unwrap();
+
+    assert_eq!(config.lsh.tables, 128);
+    assert_eq!(config.data.filename, "12");
+    assert_eq!(config.data.num_data, 1000);
+
+    assert!(config.set_from_str("lsh.k", "four").is_err());
+    assert!(config.set_from_str("lsh.l", "4").is_err());
+
+    config.set_from_str("rerank", "jaccard").unwrap();
+    assert_eq!(config.rerank, Some(Similarity::Jaccard));
+    config.set_from_str("rerank", "none").unwrap();
+    assert_eq!(config.rerank, None);
+    assert!(config.set_from_str("rerank", "dot").is_err());
+
+    config.set_from_str("label", "-1").unwrap();
+    assert_eq!(config.label.as_deref(), Some("-1"));
+    config.set_from_str("label", "1").unwrap();
+    assert_eq!(config.label.as_deref(), Some("1"));
+
+    config.set_from_str("memory_budget", "4096").unwrap();
+    assert_eq!(config.memory_budget, Some(4096));
+    config.set_from_str("memory_budget", "1.5GB").unwrap();
+    assert_eq!(config.memory_budget, Some(3 << 29));
+    config.set_from_str("memory_budget", "512 m").unwrap();
+    assert_eq!(config.memory_budget, Some(512 << 20));
+    assert!(config.set_from_str("memory_budget", "8 gigs").is_err());
+    assert!(config.set_from_str("memory_budget", "GB").is_err());
+
+    config.rerank = Some(Similarity::Cosine);
+    config.set_from_str("candidates", "5").unwrap();
+    config.data.num_data = 10;
+    match config.validate() {
+      Err(ConfigError::Invalid(v)) => {
+        assert_eq!(v.len(), 1);
+        assert!(v[0].contains("candidates"));
+      }
+      _ => panic!("expected validation errors"),
+    }
+  }
+
+  #[test]
+  fn test_validate() {
+    let mut config = Config::default();
+    config.data.filename = String::from("data.svm");
+    config.data.num_data = 100;
+    assert!(config.validate().is_ok());
+
+    let mut bad = config.clone();
+    bad.lsh.k = 8;
+    bad.lsh.tables = 64;
+    bad.lsh.range_pow = 8;
+    bad.lsh.reservoir_size = 0;
+    bad.lsh.probes = 256;
+    bad.simk = bad.topk + 1;
+    match bad.validate() {
+      Err(ConfigError::Invalid(v)) => {
+        assert_eq!(v.len(), 4);
+        assert!(v[0].contains("8 * 64 = 512"));
+        assert!(v[1].contains("reservoir_size"));
+        assert!(v[2].contains("probes"));
+        assert!(v[3].contains("simk"));
+      }
+      _ => panic!("expected validation errors"),
+    }
+
+    // Multi-probing can replace any non-empty set of a table's k min-hashes.
+    let mut probes = config.clone();
+    probes.lsh.k = 2;
+    probes.lsh.probes = 3;
+    assert!(probes.validate().is_ok());
+    probes.lsh.probes = 4;
+    match probes.validate() {
+      Err(ConfigError::Invalid(v)) => {
+        assert_eq!(v.len(), 1);
+        assert!(v[0].contains("at most 2^lsh.k - 1 (3)"));
+      }
+      _ => panic!("expected validation errors"),
+    }
+
+    // The index fits in a usize when counted in IDs but not in bytes.
+    let mut huge = config.clone();
+    huge.lsh.k = 1;
+    huge.lsh.tables = 1 << 31;
+    huge.lsh.range_pow = 31;
+    huge.lsh.reservoir_size = 1;
+    match huge.validate() {
+      Err(ConfigError::Invalid(v)) => {
+        assert_eq!(v.len(), 1);
+        assert!(v[0].contains("index size in bytes"));
+      }
+      _ => panic!("expected validation errors"),
+    }
+
+    let mut bad = config.clone();
+    bad.lsh.range_pow = 32;
+    bad.data.filename = String::new();
+    match bad.validate() {
+      Err(ConfigError::Invalid(v)) => {
+        assert_eq!(v.len(), 2);
+        assert!(v[0].contains("range_pow"));
+        assert!(v[1].contains("filename"));
+      }
+      _ => panic!("expected validation errors"),
+    }
+
+    let mut bad = config;
+    bad.lsh.tables = 0;
+    bad.lsh.k = 0;
+    bad.lsh.range_pow = 0;
+    bad.data.num_data = 0;
+    bad.topk = 0;
+    match bad.validate() {
+      Err(ConfigError::Invalid(v)) => assert_eq!(v.len(), 6),
+      _ => panic!("expected validation errors"),
+    }
+  }
 }
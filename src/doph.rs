@@ -1,12 +1,22 @@
 use crate::heap_array::HeapAllocatedArray;
 use crate::lsh::HashType;
+use crate::persist;
+use crate::persist::{invalid_data, Decoder, Encoder};
 use crate::reader::SVMData;
 
-use rand::{thread_rng, Rng};
-// use std::num::Wrapping;
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use std::io;
+use std::io::{Read, Write};
 
 const MAX_DENSIFY_RETRY: HashType = 100;
 
+const DOPH_MAGIC: &[u8; 8] = b"FLASHDPH";
+/// Version 2 hashes with wrapping arithmetic, puts the remainder of the hash range in the last
+/// bin and densifies from bins that exist. Hash functions saved by version 1 produce different
+/// hashes, so indexes built with them have to be rebuilt.
+const DOPH_VERSION: u32 = 2;
+
 pub struct DOPH {
   k: usize,
   l: usize,
@@ -22,20 +32,45 @@ pub struct DOPH {
 
 impl DOPH {
   pub fn new(l: usize, k: usize, range_pow: HashType) -> DOPH {
-    let num_hashes = k * l;
+    DOPH::with_rng(l, k, range_pow, &mut thread_rng())
+  }
 
-    let mut log_num_hash = 1;
-    while log_num_hash * 2 < (num_hashes as HashType) {
-      log_num_hash *= 2;
-    }
+  /// Creates the same hash functions every time it is called with the same arguments.
+  pub fn from_seed(l: usize, k: usize, range_pow: HashType, seed: u64) -> DOPH {
+    DOPH::with_rng(l, k, range_pow, &mut StdRng::seed_from_u64(seed))
+  }
 
-    let mut rng = thread_rng();
+  /// Draws the hash function parameters from `rng`, so the same generator state always produces
+  /// the same hash functions.
+  pub fn with_rng<R: Rng>(l: usize, k: usize, range_pow: HashType, rng: &mut R) -> DOPH {
+    let num_hashes = k * l;
 
     let mut seeds = HeapAllocatedArray::new(num_hashes);
     for i in 0..num_hashes {
       seeds[i] = rng.gen();
     }
 
+    let randa = rng.gen();
+    let randb = rng.gen();
+
+    DOPH::from_parts(l, k, range_pow, seeds, randa, randb)
+  }
+
+  fn from_parts(
+    l: usize,
+    k: usize,
+    range_pow: HashType,
+    seeds: HeapAllocatedArray<HashType>,
+    randa: HashType,
+    randb: HashType,
+  ) -> DOPH {
+    let num_hashes = k * l;
+
+    let mut log_num_hash = 1;
+    while (1 << log_num_hash) < num_hashes {
+      log_num_hash += 1;
+    }
+
     DOPH {
       k: k,
       l: l,
@@ -44,29 +79,115 @@ impl DOPH {
       log_num_hash: log_num_hash,
       binsize: (1 << range_pow) / ((num_hashes) as HashType),
       seeds: seeds,
-      randa: rng.gen(),
-      randb: rng.gen(),
+      randa: randa,
+      randb: randb,
+    }
+  }
+
+  pub fn tables(&self) -> usize {
+    self.l
+  }
+
+  /// The number of min-hash values concatenated to form each table's hash.
+  pub fn k(&self) -> usize {
+    self.k
+  }
+
+  /// Saves every parameter of the hash functions so that `load` reproduces them exactly.
+  pub fn save(&self, filename: &str) -> io::Result<()> {
+    persist::save_atomic(filename, |e| self.write_to(e))
+  }
+
+  pub fn load(filename: &str) -> io::Result<DOPH> {
+    persist::load(filename, DOPH::read_from)
+  }
+
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    e.write_header(DOPH_MAGIC, DOPH_VERSION)?;
+    e.write_u64(self.l as u64)?;
+    e.write_u64(self.k as u64)?;
+    e.write_u32(self.range_pow)?;
+    e.write_u32(self.randa)?;
+    e.write_u32(self.randb)?;
+    e.write_checksum()?;
+
+    e.write_u32_slice(&self.seeds)?;
+    e.write_checksum()
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<DOPH> {
+    let version = d.read_header(DOPH_MAGIC, DOPH_VERSION)?;
+    if version < DOPH_VERSION {
+      return Err(invalid_data(format!(
+        "hash functions of format version {} hash differently, rebuild the index",
+        version
+      )));
     }
+    let l = d.read_usize()?;
+    let k = d.read_usize()?;
+    let range_pow = d.read_u32()?;
+    let randa = d.read_u32()?;
+    let randb = d.read_u32()?;
+    d.verify_checksum("hash function header")?;
+
+    let valid = l > 0
+      && k > 0
+      && range_pow > 0
+      && range_pow < 32
+      && k.checked_mul(l).is_some_and(|n| n <= 1 << range_pow);
+    if !valid {
+      return Err(invalid_data(format!(
+        "invalid hash function parameters: l = {}, k = {}, range_pow = {}",
+        l, k, range_pow
+      )));
+    }
+
+    let seeds = d.read_u32_array(k * l)?;
+    d.verify_checksum("hash function seeds")?;
+
+    Ok(DOPH::from_parts(l, k, range_pow, seeds, randa, randb))
+  }
+
+  pub fn hash(&self, data: &SVMData) -> HeapAllocatedArray<HashType> {
+    self.hash_with_probes(data, 0)
   }
 
-  pub fn hash(&self, data: SVMData) -> HeapAllocatedArray<HashType> {
-    let mut hashes_indices = HeapAllocatedArray::with_default(self.l * data.len());
+  /// Hashes each vector to its bucket in every table followed by `probes` further buckets to
+  /// visit in that table when multi-probe querying, giving `l * (probes + 1)` hashes per vector.
+  ///
+  /// A table's hash combines `k` min-hash values. A similar vector that lands in a different
+  /// bucket usually lacks the element giving one of those minimums, in which case its minimum for
+  /// that bin is most likely our second smallest hash in the bin. The probes therefore recombine
+  /// the table's min-hashes with one of them replaced by the bin's second smallest hash, trying
+  /// each component in turn, then every pair of components, and so on. Probes that cannot change
+  /// anything, because a bin had a single element, repeat the vector's own bucket.
+  pub fn hash_with_probes(&self, data: &SVMData, probes: usize) -> HeapAllocatedArray<HashType> {
+    let per_table = probes + 1;
+    let mut hashes_indices = HeapAllocatedArray::with_default(self.l * per_table * data.len());
+
+    let swaps = probe_swaps(self.k, probes);
 
     let mut hashes: HeapAllocatedArray<HashType> = HeapAllocatedArray::new(self.num_hashes);
+    let mut seconds: HeapAllocatedArray<HashType> = HeapAllocatedArray::new(self.num_hashes);
     let mut min_hashes: HeapAllocatedArray<HashType> = HeapAllocatedArray::new(self.num_hashes);
+    let mut second_hashes: HeapAllocatedArray<HashType> = HeapAllocatedArray::new(self.num_hashes);
 
     for n in 0..data.len() {
       min_hashes.fill(HashType::MAX);
+      second_hashes.fill(HashType::MAX);
       // Compute min-hash for each bin
       for i in data.markers[n]..data.markers[n + 1] {
         let val = data.indices[i];
-        let mut h = val * self.randa;
+        let mut h = val.wrapping_mul(self.randa);
         h ^= h >> 13;
-        h *= 0x85ebca6b;
-        let final_hash = (h * val << 5) >> (32 - self.range_pow);
-        let bin = final_hash / self.binsize;
-        if min_hashes[bin as usize] > final_hash {
-          min_hashes[bin as usize] = final_hash;
+        h = h.wrapping_mul(0x85ebca6b);
+        let final_hash = (h.wrapping_mul(val) << 5) >> (32 - self.range_pow);
+        let bin = self.bin(final_hash);
+        if min_hashes[bin] > final_hash {
+          second_hashes[bin] = min_hashes[bin];
+          min_hashes[bin] = final_hash;
+        } else if min_hashes[bin] != final_hash && second_hashes[bin] > final_hash {
+          second_hashes[bin] = final_hash;
         }
       }
 
@@ -75,16 +196,16 @@ impl DOPH {
         let mut next = min_hashes[i];
         if next != HashType::MAX {
           hashes[i] = next;
+          seconds[i] = second_hashes[i];
           continue;
         }
+        let mut second = HashType::MAX;
         let mut cnt: HashType = 0;
         while next == HashType::MAX {
           cnt += 1;
-          let idx = std::cmp::min(
-            self.rand_hash(i as HashType, cnt),
-            self.num_hashes as HashType,
-          );
-          next = min_hashes[idx as usize];
+          let idx = self.densify_index(i, cnt);
+          next = min_hashes[idx];
+          second = second_hashes[idx];
           if cnt >= MAX_DENSIFY_RETRY {
             next = 0; // TODO: Default value?
             eprintln!("Densification Failure");
@@ -93,54 +214,304 @@ impl DOPH {
         }
 
         hashes[i] = next;
+        seconds[i] = second;
       }
 
       // Combine L * K hashes into L hashes
       for t in 0..self.l {
-        let mut idx = 0;
+        let mut idx: HashType = 0;
         for i in 0..self.k {
-          let val = hashes[t * self.k + i];
-          let mut h = val * self.seeds[t * self.k + i];
-          h ^= h >> 13;
-          h ^= self.seeds[t * self.k + i];
-          idx += h * val;
+          idx = self
+            .component(t, i, hashes[t * self.k + i])
+            .wrapping_add(idx);
         }
-        idx = (idx << 2) >> (32 - self.range_pow);
 
-        hashes_indices[n * self.l + t] = idx;
+        let start = (n * self.l + t) * per_table;
+        hashes_indices[start] = (idx << 2) >> (32 - self.range_pow);
+
+        for p in 0..probes {
+          let mut probe = idx;
+          for &i in swaps.get(p).map_or(&[][..], |s| &s[..]) {
+            let second = seconds[t * self.k + i];
+            if second != HashType::MAX {
+              probe = probe
+                .wrapping_sub(self.component(t, i, hashes[t * self.k + i]))
+                .wrapping_add(self.component(t, i, second));
+            }
+          }
+          hashes_indices[start + p + 1] = (probe << 2) >> (32 - self.range_pow);
+        }
       }
     }
 
     return hashes_indices;
   }
 
+  /// The contribution of the `i`th min-hash value `val` of table `t` to that table's hash.
+  fn component(&self, t: usize, i: usize, val: HashType) -> HashType {
+    let seed = self.seeds[t * self.k + i];
+    let mut h = val.wrapping_mul(seed);
+    h ^= h >> 13;
+    h ^= seed;
+    return h.wrapping_mul(val);
+  }
+
+  /// The bin of a min-hash value. The last bin absorbs the remainder when `num_hashes` does not
+  /// divide `2^range_pow`, since dividing by `binsize` alone can give `num_hashes`.
+  fn bin(&self, final_hash: HashType) -> usize {
+    std::cmp::min(final_hash / self.binsize, self.num_hashes as HashType - 1) as usize
+  }
+
+  /// The bin an empty `bin` borrows its min-hash from on attempt `count`. `rand_hash` is below
+  /// the next power of two of `num_hashes`, so it is reduced to a valid bin.
+  fn densify_index(&self, bin: usize, count: HashType) -> usize {
+    (self.rand_hash(bin as HashType, count) % self.num_hashes as HashType) as usize
+  }
+
   fn rand_hash(&self, bin: HashType, count: HashType) -> HashType {
     let temp = ((bin + 1) << 10) + count;
-    return (self.randb * temp << 3) >> (32 - self.log_num_hash);
+    return (self.randb.wrapping_mul(temp) << 3) >> (32 - self.log_num_hash);
+  }
+}
+
+/// The number of distinct probes a table with `k` min-hashes can produce, one per non-empty set
+/// of components to replace. Further probes repeat the vector's own bucket.
+pub fn max_probes(k: usize) -> usize {
+  if k >= usize::BITS as usize {
+    return usize::MAX;
+  }
+  return (1 << k) - 1;
+}
+
+/// Lists which components each of the first `probes` probes replaces: every single component,
+/// then every pair, and so on, stopping early once all `2^k - 1` non-empty sets are used.
+fn probe_swaps(k: usize, probes: usize) -> Vec<Vec<usize>> {
+  let mut swaps = Vec::new();
+  for size in 1..k + 1 {
+    let mut combo: Vec<usize> = (0..size).collect();
+    loop {
+      if swaps.len() >= probes {
+        return swaps;
+      }
+      swaps.push(combo.clone());
+
+      // Advance to the next combination in lexicographic order.
+      let mut i = size;
+      while i > 0 && combo[i - 1] == k - size + i - 1 {
+        i -= 1;
+      }
+      if i == 0 {
+        break;
+      }
+      combo[i - 1] += 1;
+      for j in i..size {
+        combo[j] = combo[j - 1] + 1;
+      }
+    }
   }
+  return swaps;
 }
 
-// #[cfg(test)]
-// mod tests {
-//   use super::*;
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_data() -> SVMData {
+    SVMData {
+      markers: vec![0, 4, 5, 7, 10],
+      indices: vec![88, 91, 120, 18223, 4, 177, 12, 5, 66, 9001],
+      values: vec![-1.0, 0.125, 0.0, -2.125, -0.5, -83.5, 56.25, 1.0, 2.0, 3.0],
+      len: 4,
+    }
+  }
+
+  #[test]
+  fn test_doph() {
+    let markers = vec![0, 4, 5, 7];
+    let indices = vec![88, 91, 120, 18223, 4, 177, 12];
+    let values = vec![-1.0, 0.125, 0.0, -2.125, -0.5, -83.5, 56.25];
+
+    let data = SVMData {
+      markers,
+      indices,
+      values,
+      len: 3,
+    };
+
+    let doph = DOPH::new(4, 4, 4);
+
+    let hashes = doph.hash(&data);
+
+    assert_eq!(hashes.len(), 12);
+    for &h in hashes.iter() {
+      assert!(h < 16);
+    }
+  }
+
+  #[test]
+  fn test_bins_in_range() {
+    // 48 bins of 2^10 / 48 = 21 hashes leave 16 hashes over, which dividing alone puts in a bin
+    // past the last one.
+    let doph = DOPH::from_seed(12, 4, 10, 1);
+    assert_eq!(doph.binsize, 21);
+    assert_eq!(1023 / doph.binsize, 48);
+    for final_hash in 0..1 << 10 {
+      assert!(doph.bin(final_hash) < 48);
+    }
+    assert_eq!(doph.bin(1023), 47);
+  }
+
+  #[test]
+  fn test_densify_index_in_range() {
+    for &(l, k) in &[(4, 4), (12, 4), (3, 3)] {
+      let doph = DOPH::from_seed(l, k, 12, 2);
+      let num_hashes = l * k;
+
+      // `log_num_hash` used to be the largest power of two below `num_hashes` rather than its
+      // log, so `rand_hash` ranged far past the last bin.
+      let mut old_log = 1;
+      while old_log * 2 < num_hashes {
+        old_log *= 2;
+      }
+      assert!(1 << old_log > 2 * num_hashes);
+      assert!(1 << doph.log_num_hash >= num_hashes);
+      assert!(1 << (doph.log_num_hash - 1) < num_hashes);
+
+      // Clamping `rand_hash` to `num_hashes` with `min` still indexed one past the last bin.
+      let mut past_end = 0;
+      for bin in 0..num_hashes {
+        for count in 1..MAX_DENSIFY_RETRY + 1 {
+          if doph.rand_hash(bin as HashType, count) >= num_hashes as HashType {
+            past_end += 1;
+          }
+          assert!(doph.densify_index(bin, count) < num_hashes);
+        }
+      }
+      if !num_hashes.is_power_of_two() {
+        assert!(past_end > 0);
+      }
+    }
+  }
+
+  #[test]
+  fn test_doph_uneven_bins() {
+    let data = SVMData {
+      markers: vec![0, 3, 3, 4],
+      indices: vec![7, 4000000000, 123456, 1],
+      values: vec![1.0, 1.0, 1.0, 1.0],
+      len: 3,
+    };
 
-//   #[test]
-//   fn test_doph() {
-//     let markers = vec![0, 4, 5, 7];
-//     let indices = vec![88, 91, 120, 18223, 4, 177, 12];
-//     let values = vec![-1.0, 0.125, 0.0, -2.125, -0.5, -83.5, 56.25];
+    // 48 does not divide 2^10 and most bins are empty, so this exercises both the remainder
+    // bin and densification.
+    // 4000000000 also overflows every multiplication, which used to panic in debug builds.
+    let doph = DOPH::new(12, 4, 10);
 
-//     let data = SVMData {
-//       markers,
-//       indices,
-//       values,
-//       len: 3,
-//     };
+    let hashes = doph.hash(&data);
 
-//     let doph = DOPH::new(4, 4, 4);
+    assert_eq!(hashes.len(), 36);
+    for &h in hashes.iter() {
+      assert!(h < 1 << 10);
+    }
+  }
+
+  #[test]
+  fn test_from_seed() {
+    let a = DOPH::from_seed(8, 4, 12, 17).hash(&test_data());
+    let b = DOPH::from_seed(8, 4, 12, 17).hash(&test_data());
+    let c = DOPH::from_seed(8, 4, 12, 18).hash(&test_data());
+
+    assert_eq!(*a, *b);
+    assert_ne!(*a, *c);
+  }
+
+  #[test]
+  fn test_probe_swaps() {
+    assert_eq!(
+      probe_swaps(3, 10),
+      vec![
+        vec![0],
+        vec![1],
+        vec![2],
+        vec![0, 1],
+        vec![0, 2],
+        vec![1, 2],
+        vec![0, 1, 2]
+      ]
+    );
+    assert_eq!(
+      probe_swaps(4, 5),
+      vec![vec![0], vec![1], vec![2], vec![3], vec![0, 1]]
+    );
+    assert!(probe_swaps(4, 0).is_empty());
+    assert_eq!(probe_swaps(4, 100).len(), max_probes(4));
+  }
 
-//     let hashes = doph.hash(data);
+  #[test]
+  fn test_hash_with_probes() {
+    let doph = DOPH::from_seed(8, 4, 12, 17);
+    let base = doph.hash(&test_data());
+    assert_eq!(*doph.hash_with_probes(&test_data(), 0), *base);
 
-//     assert_eq!(hashes.len(), 12);
-//   }
-// }
+    let probes = 6;
+    let probed = doph.hash_with_probes(&test_data(), probes);
+    assert_eq!(probed.len(), base.len() * (probes + 1));
+    for (i, group) in probed.chunks(probes + 1).enumerate() {
+      assert_eq!(group[0], base[i]);
+      assert!(group.iter().all(|&h| h < 1 << 12));
+    }
+
+    // Removing one element changes at most one min-hash to the bin's second smallest hash, so
+    // every table whose bucket changes should find the new bucket among its single swap probes.
+    let full: Vec<u32> = (0..1000).map(|x| x * 37 + 5).collect();
+    let vector = |indices: Vec<u32>| SVMData {
+      markers: vec![0, indices.len()],
+      values: vec![1.0; indices.len()],
+      indices,
+      len: 1,
+    };
+    let probed = doph.hash_with_probes(&vector(full.clone()), 4);
+    let mut moved = 0;
+    for removed in 0..100 {
+      let mut indices = full.clone();
+      indices.remove(removed * 10);
+      let hashes = doph.hash(&vector(indices));
+      for t in 0..8 {
+        let group = &(*probed)[t * 5..t * 5 + 5];
+        if hashes[t] != group[0] {
+          moved += 1;
+          assert!(group[1..].contains(&hashes[t]));
+        }
+      }
+    }
+    assert!(moved > 0);
+  }
+
+  #[test]
+  fn test_save_load() {
+    let doph = DOPH::new(8, 4, 12);
+
+    let filename = "./temp_doph_save_test_file";
+    doph.save(filename).expect("hash functions should save");
+    let loaded = DOPH::load(filename).expect("hash functions should load");
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+
+    assert_eq!(loaded.l, doph.l);
+    assert_eq!(loaded.k, doph.k);
+    assert_eq!(loaded.range_pow, doph.range_pow);
+    assert_eq!(loaded.log_num_hash, doph.log_num_hash);
+    assert_eq!(loaded.binsize, doph.binsize);
+    assert_eq!(*loaded.seeds, *doph.seeds);
+    assert_eq!(*loaded.hash(&test_data()), *doph.hash(&test_data()));
+
+    // Hash functions from before the hashing fixes are rejected rather than hashing differently.
+    let mut e = Encoder::new(Vec::new());
+    doph.write_to(&mut e).unwrap();
+    let mut bytes = e.into_inner();
+    bytes[8..12].copy_from_slice(&1u32.to_le_bytes());
+    let err = DOPH::read_from(&mut Decoder::new(&bytes[..]))
+      .err()
+      .unwrap();
+    assert!(err.to_string().contains("rebuild the index"));
+  }
+}
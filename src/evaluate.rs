@@ -1,19 +1,63 @@
-use crate::lsh::QueryResult;
+use crate::lsh::{IDType, QueryResult};
 use crate::reader::SVMData;
 
-fn sparse_multiply(a: usize, b: usize, data: &SVMData) -> f32 {
-  let mut ia = data.markers[a];
-  let ea = data.markers[a + 1];
-  let mut ib = data.markers[b];
-  let eb = data.markers[b + 1];
+/// How re-ranking measures the similarity of two vectors.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Similarity {
+  /// Cosine of the angle between the vectors.
+  Cosine,
+  /// Jaccard similarity of the sets of non-zero indices, ignoring the values.
+  Jaccard,
+}
+
+impl Similarity {
+  pub const NAMES: &'static str = "\"cosine\" or \"jaccard\"";
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Similarity::Cosine => "cosine",
+      Similarity::Jaccard => "jaccard",
+    }
+  }
+
+  /// The similarity of vector `x` of `a` and vector `y` of `b`.
+  pub fn between(&self, a: &SVMData, x: usize, b: &SVMData, y: usize) -> f32 {
+    match self {
+      Similarity::Cosine => cosine_similarity(a, x, b, y),
+      Similarity::Jaccard => jaccard_similarity(a, x, b, y),
+    }
+  }
+}
+
+impl std::str::FromStr for Similarity {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "cosine" => Ok(Similarity::Cosine),
+      "jaccard" => Ok(Similarity::Jaccard),
+      _ => Err(format!(
+        "unknown similarity '{}', expected one of {}",
+        s,
+        Similarity::NAMES
+      )),
+    }
+  }
+}
+
+fn sparse_multiply(a: &SVMData, x: usize, b: &SVMData, y: usize) -> f32 {
+  let mut ia = a.markers[x];
+  let ea = a.markers[x + 1];
+  let mut ib = b.markers[y];
+  let eb = b.markers[y + 1];
 
   let mut total = 0.0;
   while ia < ea && ib < eb {
-    if data.indices[ia] == data.indices[ib] {
-      total += data.values[ia] * data.values[ib];
+    if a.indices[ia] == b.indices[ib] {
+      total += a.values[ia] * b.values[ib];
       ia += 1;
       ib += 1;
-    } else if data.indices[ia] < data.indices[ib] {
+    } else if a.indices[ia] < b.indices[ib] {
       ia += 1;
     } else {
       ib += 1;
@@ -36,29 +80,191 @@ fn magnitude(x: usize, data: &SVMData) -> f32 {
   return total.sqrt();
 }
 
-pub fn average_cosine_similarity(
-  query_start: usize,
-  query_count: usize,
-  results: QueryResult,
+pub fn cosine_similarity(a: &SVMData, x: usize, b: &SVMData, y: usize) -> f32 {
+  let magnitudes = magnitude(x, a) * magnitude(y, b);
+  if magnitudes == 0.0 {
+    return 0.0;
+  }
+  return sparse_multiply(a, x, b, y) / magnitudes;
+}
+
+pub fn jaccard_similarity(a: &SVMData, x: usize, b: &SVMData, y: usize) -> f32 {
+  let mut ia = a.markers[x];
+  let ea = a.markers[x + 1];
+  let mut ib = b.markers[y];
+  let eb = b.markers[y + 1];
+  let total = (ea - ia) + (eb - ib);
+
+  let mut common = 0;
+  while ia < ea && ib < eb {
+    if a.indices[ia] == b.indices[ib] {
+      common += 1;
+      ia += 1;
+      ib += 1;
+    } else if a.indices[ia] < b.indices[ib] {
+      ia += 1;
+    } else {
+      ib += 1;
+    }
+  }
+
+  if total == 0 {
+    return 0.0;
+  }
+  return common as f32 / (total - common) as f32;
+}
+
+/// Computes the exact similarity between each query in `queries` and every one of its candidates,
+/// and keeps the `k` most similar, best first with ties broken by ID. Candidate IDs are the rows
+/// of `data` holding their vectors. The queries are split across `threads` threads.
+pub fn rerank<S: Copy + Default + Sync>(
+  candidates: &QueryResult<S>,
+  queries: &SVMData,
+  data: &SVMData,
+  k: usize,
+  metric: Similarity,
+  threads: usize,
+) -> QueryResult<f32> {
+  rerank_rows(
+    candidates,
+    queries,
+    data,
+    |id| Some(id as usize),
+    k,
+    metric,
+    threads,
+  )
+}
+
+/// Same as `rerank` but `row_of` gives the row of `data` holding each candidate's vector.
+/// Candidates without a vector are dropped.
+pub fn rerank_rows<S, F>(
+  candidates: &QueryResult<S>,
+  queries: &SVMData,
+  data: &SVMData,
+  row_of: F,
+  k: usize,
+  metric: Similarity,
+  threads: usize,
+) -> QueryResult<f32>
+where
+  S: Copy + Default + Sync,
+  F: Fn(IDType) -> Option<usize> + Sync,
+{
+  let num_query = candidates.len();
+  let threads = std::cmp::max(1, std::cmp::min(threads, num_query));
+  let per_thread = std::cmp::max(1, num_query.div_ceil(threads));
+  let row_of = &row_of;
+
+  let rows: Vec<Vec<(IDType, f32)>> = std::thread::scope(|scope| {
+    let handles: Vec<_> = (0..num_query)
+      .step_by(per_thread)
+      .map(|first| {
+        scope.spawn(move || {
+          let last = std::cmp::min(first + per_thread, num_query);
+          (first..last)
+            .map(|q| {
+              let mut row: Vec<(IDType, f32)> = candidates
+                .ids(q)
+                .filter_map(|id| Some((id, metric.between(queries, q, data, row_of(id)?))))
+                .collect();
+              row.sort_by(|a, b| b.1.total_cmp(&a.1).then(a.0.cmp(&b.0)));
+              row.truncate(k);
+              row
+            })
+            .collect::<Vec<_>>()
+        })
+      })
+      .collect();
+    handles
+      .into_iter()
+      .flat_map(|h| h.join().expect("re-ranking thread should not panic"))
+      .collect()
+  });
+
+  return QueryResult::from_rows(&rows);
+}
+
+/// Averages the cosine similarity between each query in `queries` and its top `k` results in the
+/// matching row of `results`, where `row_of` gives the row of `data` holding each result's
+/// vector. Results without a vector are skipped.
+pub fn average_cosine_similarity<S, F>(
+  results: &QueryResult<S>,
+  queries: &SVMData,
   data: &SVMData,
+  row_of: F,
   k: usize,
-) -> f32 {
+) -> f32
+where
+  S: Copy + Default,
+  F: Fn(IDType) -> Option<usize>,
+{
   let mut total = 0.0;
   let mut count = 0;
-  for q in query_start..(query_start + query_count) {
-    let mut c = 0;
-    for r in results.nth(q) {
-      let sim =
-        sparse_multiply(q, r as usize, data) / (magnitude(q, data) * magnitude(r as usize, data));
-
-      total += sim;
+  for q in 0..queries.len() {
+    for r in results.ids(q).take(k).filter_map(&row_of) {
+      total += cosine_similarity(queries, q, data, r);
       count += 1;
-      c += 1;
-      if c >= k {
-        break;
-      }
     }
   }
 
   return total / count as f32;
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_data() -> SVMData {
+    SVMData {
+      markers: vec![0, 3, 5, 8, 8, 11],
+      indices: vec![1, 4, 9, 1, 4, 2, 4, 9, 1, 4, 9],
+      values: vec![1.0, 2.0, 2.0, 1.0, 2.0, 1.0, 2.0, 2.0, 1.0, 2.0, 2.0],
+      len: 5,
+    }
+  }
+
+  #[test]
+  fn test_similarity() {
+    let data = test_data();
+
+    assert!((cosine_similarity(&data, 0, &data, 0) - 1.0).abs() < 1e-6);
+    assert!((cosine_similarity(&data, 0, &data, 1) - 5.0 / (3.0 * 5f32.sqrt())).abs() < 1e-6);
+    assert!((cosine_similarity(&data, 0, &data, 2) - 8.0 / 9.0).abs() < 1e-6);
+    assert_eq!(cosine_similarity(&data, 0, &data, 3), 0.0);
+    assert_eq!(cosine_similarity(&data, 3, &data, 3), 0.0);
+
+    assert_eq!(jaccard_similarity(&data, 0, &data, 0), 1.0);
+    assert_eq!(jaccard_similarity(&data, 0, &data, 1), 2.0 / 3.0);
+    assert_eq!(jaccard_similarity(&data, 0, &data, 2), 0.5);
+    assert_eq!(jaccard_similarity(&data, 0, &data, 3), 0.0);
+    assert_eq!(jaccard_similarity(&data, 3, &data, 3), 0.0);
+
+    assert_eq!("jaccard".parse(), Ok(Similarity::Jaccard));
+    assert_eq!(Similarity::Cosine.name().parse(), Ok(Similarity::Cosine));
+    assert!("dot".parse::<Similarity>().is_err());
+  }
+
+  #[test]
+  fn test_rerank() {
+    let data = test_data();
+    // Candidates in collision order, which re-ranking should replace with similarity order.
+    let candidates =
+      QueryResult::from_rows(&[vec![(1, 4), (2, 3), (0, 1)], vec![(4, 2), (2, 2), (0, 1)]]);
+
+    for &threads in &[1, 2] {
+      let cosine = rerank(&candidates, &data, &data, 2, Similarity::Cosine, threads);
+      assert_eq!(cosine.len(), 2);
+      assert_eq!(cosine.ids(0).collect::<Vec<_>>(), vec![0, 2]);
+      let best: Vec<f32> = cosine.nth(0).map(|(_, s)| s).collect();
+      assert!((best[0] - 1.0).abs() < 1e-6 && (best[1] - 8.0 / 9.0).abs() < 1e-6);
+
+      // Items 0 and 4 are identical, so the lower ID comes first.
+      let jaccard = rerank(&candidates, &data, &data, 2, Similarity::Jaccard, threads);
+      assert_eq!(
+        jaccard.nth(1).collect::<Vec<_>>(),
+        vec![(0, 2.0 / 3.0), (4, 2.0 / 3.0)]
+      );
+    }
+  }
+}
@@ -2,7 +2,7 @@ use std::alloc::{alloc, dealloc, Layout};
 use std::fmt;
 use std::marker::PhantomData;
 use std::mem;
-use std::ops::{Deref, Index, IndexMut};
+use std::ops::{Deref, DerefMut, Index, IndexMut};
 use std::ptr;
 
 struct MemPtr<T> {
@@ -38,6 +38,16 @@ impl<T> HeapAllocatedArray<T> {
     let size = mem::size_of::<T>();
     let layout = Layout::from_size_align(size * len, alignment).expect("Layout should be valid");
 
+    // A zero-sized layout cannot be passed to `alloc`, so empty arrays use a dangling pointer and
+    // are never deallocated.
+    if layout.size() == 0 {
+      return HeapAllocatedArray {
+        ptr: MemPtr::new(ptr::NonNull::dangling().as_ptr()),
+        len: len,
+        layout: layout,
+      };
+    }
+
     unsafe {
       let ptr = alloc(layout) as *mut T;
       HeapAllocatedArray {
@@ -75,6 +85,10 @@ impl<T> HeapAllocatedArray<T> {
   pub fn len(&self) -> usize {
     self.len
   }
+
+  pub fn as_mut_ptr(&mut self) -> *mut T {
+    self.ptr.ptr()
+  }
 }
 
 impl<T> HeapAllocatedArray<T>
@@ -91,6 +105,9 @@ where
 
 impl<T> Drop for HeapAllocatedArray<T> {
   fn drop(&mut self) {
+    if self.layout.size() == 0 {
+      return;
+    }
     unsafe {
       dealloc(self.ptr.ptr() as *mut u8, self.layout);
     }
@@ -147,6 +164,12 @@ impl<T> Deref for HeapAllocatedArray<T> {
   }
 }
 
+impl<T> DerefMut for HeapAllocatedArray<T> {
+  fn deref_mut(&mut self) -> &mut [T] {
+    unsafe { std::slice::from_raw_parts_mut(self.ptr.ptr(), self.len()) }
+  }
+}
+
 impl<T> HeapAllocatedArray<T>
 where
   T: Default,
@@ -247,6 +270,18 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_deref_mut() {
+    let mut arr: HeapAllocatedArray<usize> = HeapAllocatedArray::with_default(6);
+
+    for x in (*arr)[2..5].iter_mut() {
+      *x = 9;
+    }
+    arr.chunks_mut(3).for_each(|c| c[0] += 1);
+
+    assert_eq!(*arr, [1, 0, 9, 10, 9, 0]);
+  }
+
   #[test]
   fn test_with_default() {
     let h: HeapAllocatedArray<usize> = HeapAllocatedArray::with_default(1000);
@@ -265,6 +300,14 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_empty() {
+    let h: HeapAllocatedArray<u32> = HeapAllocatedArray::with_default(0);
+    assert_eq!(0, h.len());
+    assert!(h.iter().next().is_none());
+    assert_eq!(h.to_string(), "[]");
+  }
+
   #[test]
   fn test_sort() {
     let a = [56, 3, -47, 34, 33, 5, -2, 34, 0, 6, -11, 5, 1];
@@ -0,0 +1,681 @@
+use crate::doph::DOPH;
+use crate::evaluate::{rerank_rows, Similarity};
+use crate::keys::KeyMap;
+use crate::labels::Labels;
+use crate::lsh::{Filter, IDType, QueryResult, LSH};
+use crate::persist;
+use crate::persist::{invalid_data, Decoder, Encoder};
+use crate::reader::SVMData;
+use crate::store::VectorStore;
+
+use std::io;
+use std::io::{Read, Write};
+
+const INDEX_MAGIC: &[u8; 8] = b"FLASHIDX";
+const INDEX_VERSION: u32 = 5;
+
+/// An LSH index bundled with the hash functions used to build it, so that a saved index can be
+/// queried by a later process, the vectors it holds for retrieval and re-ranking, the external
+/// keys of items inserted by key and the labels results can be filtered by.
+pub struct Index {
+  pub doph: DOPH,
+  pub lsh: LSH,
+  pub store: VectorStore,
+  /// `None` until a key map is given with `with_keys` or the first keyed insert starts one.
+  pub keys: Option<KeyMap>,
+  pub labels: Labels,
+}
+
+impl Index {
+  pub fn new(doph: DOPH, lsh: LSH) -> Self {
+    assert_eq!(
+      doph.tables(),
+      lsh.tables(),
+      "Hash functions and index should have the same number of tables"
+    );
+    Index {
+      doph,
+      lsh,
+      store: VectorStore::new(),
+      keys: None,
+      labels: Labels::new(),
+    }
+  }
+
+  /// Attaches the vectors of the indexed items.
+  pub fn with_store(mut self, store: VectorStore) -> Self {
+    self.store = store;
+    self
+  }
+
+  /// Attaches the labels of the indexed items.
+  pub fn with_labels(mut self, labels: Labels) -> Self {
+    self.labels = labels;
+    self
+  }
+
+  /// Replaces the key map, for example with one starting after the IDs already in the index.
+  pub fn with_keys(mut self, keys: KeyMap) -> Self {
+    self.keys = Some(keys);
+    self
+  }
+
+  /// Inserts the vectors in `data` under `keys`, assigning each new key an ID and replacing the
+  /// vector of keys that are already in the index. Returns the IDs of the keys. Unless a key map
+  /// was given with `with_keys`, the first key is assigned the ID after the largest one already in
+  /// the index, so keys never take the IDs of items inserted without one. Panics if the index is
+  /// frozen, as do the other methods that modify it, see `LSH::is_frozen`.
+  pub fn insert_keyed<K: AsRef<str>>(&mut self, keys: &[K], data: &SVMData) -> Vec<IDType> {
+    assert_eq!(
+      keys.len(),
+      data.len(),
+      "Should have one key for each vector"
+    );
+    if self.keys.is_none() {
+      let next_id = self.lsh.max_id().map_or(0, |id| id + 1);
+      self.keys = Some(KeyMap::starting_at(std::cmp::max(
+        next_id,
+        self.store.next_id(),
+      )));
+    }
+    let hashes = self.doph.hash(data);
+    let tables = self.lsh.tables();
+    let mut ids = Vec::with_capacity(keys.len());
+    for (i, key) in keys.iter().enumerate() {
+      let key = key.as_ref();
+      if let Some(id) = self.key_id(key) {
+        self.remove_from_lsh(id);
+      }
+      let id = self
+        .keys
+        .as_mut()
+        .expect("The key map is started above")
+        .assign(key);
+      self
+        .lsh
+        .insert(&[id], &(*hashes)[i * tables..(i + 1) * tables]);
+      self.store.insert(id, data, i);
+      ids.push(id);
+    }
+    return ids;
+  }
+
+  /// Removes the item inserted under `key`. Returns whether there was one. Panics if the index is
+  /// frozen and there was.
+  pub fn remove_key(&mut self, key: &str) -> bool {
+    match self.keys.as_mut().and_then(|keys| keys.remove(key)) {
+      Some(id) => {
+        self.remove(id);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Like `query_parallel`, but returns the keys of the results. Results inserted without a key
+  /// are left out.
+  pub fn query_keyed(&self, data: &SVMData, k: usize, threads: usize) -> Vec<Vec<(&str, u32)>> {
+    let results = self.query_parallel(data, k, threads);
+    self.resolve(&results)
+  }
+
+  /// Queries the index with the stored vectors of `keys` and returns the keys of the results.
+  /// Returns the first key without a stored vector if there is one.
+  pub fn query_by_key<'a, K: AsRef<str>>(
+    &self,
+    keys: &'a [K],
+    k: usize,
+    threads: usize,
+  ) -> Result<Vec<Vec<(&str, u32)>>, &'a str> {
+    let mut ids = Vec::with_capacity(keys.len());
+    for key in keys {
+      let key = key.as_ref();
+      match self.key_id(key) {
+        Some(id) if self.store.row(id).is_some() => ids.push(id),
+        _ => return Err(key),
+      }
+    }
+    let results = self
+      .query_by_id(&ids, k, threads)
+      .expect("Every key should have a stored vector");
+    Ok(self.resolve(&results))
+  }
+
+  /// The ID of the item inserted under `key`, if there is one.
+  pub fn key_id(&self, key: &str) -> Option<IDType> {
+    self.keys.as_ref().and_then(|keys| keys.id(key))
+  }
+
+  /// Replaces the IDs in `results` by their keys, leaving out results without one.
+  fn resolve(&self, results: &QueryResult) -> Vec<Vec<(&str, u32)>> {
+    match &self.keys {
+      Some(keys) => keys.resolve(results),
+      None => results.iter().map(|_| Vec::new()).collect(),
+    }
+  }
+
+  pub fn query(&self, data: &SVMData, k: usize) -> QueryResult {
+    let hashes = self.doph.hash(data);
+    self.lsh.query(&hashes, k)
+  }
+
+  pub fn query_parallel(&self, data: &SVMData, k: usize, threads: usize) -> QueryResult {
+    let hashes = self.doph.hash(data);
+    self.lsh.query_parallel(&hashes, k, threads)
+  }
+
+  /// Multi-probe query which also visits `probes` nearby buckets in each table, see
+  /// `DOPH::hash_with_probes`.
+  pub fn query_multiprobe(
+    &self,
+    data: &SVMData,
+    k: usize,
+    probes: usize,
+    threads: usize,
+  ) -> QueryResult {
+    let hashes = self.doph.hash_with_probes(data, probes);
+    self.lsh.query_multiprobe(&hashes, probes, k, threads)
+  }
+
+  /// Multi-probe query which only returns the items that pass `filter`, filling `k` results
+  /// whenever enough candidates pass.
+  pub fn query_filtered(
+    &self,
+    data: &SVMData,
+    k: usize,
+    probes: usize,
+    filter: &Filter,
+    threads: usize,
+  ) -> QueryResult {
+    let hashes = self.doph.hash_with_probes(data, probes);
+    self.lsh.query_filtered(&hashes, probes, k, filter, threads)
+  }
+
+  /// Multi-probe query which only returns the items labelled `label`.
+  pub fn query_with_label(
+    &self,
+    data: &SVMData,
+    k: usize,
+    probes: usize,
+    label: &str,
+    threads: usize,
+  ) -> QueryResult {
+    let code = self.labels.code(label);
+    let has_label = |id| code.is_some() && self.labels.code_of(id) == code;
+    self.query_filtered(data, k, probes, &Filter::Predicate(&has_label), threads)
+  }
+
+  /// Fetches `candidates` results for each query and re-ranks them by their exact `metric`
+  /// similarity to the query using the stored vectors, keeping the best `k`.
+  pub fn query_reranked(
+    &self,
+    queries: &SVMData,
+    k: usize,
+    candidates: usize,
+    metric: Similarity,
+    threads: usize,
+  ) -> QueryResult<f32> {
+    let found = self.query_parallel(queries, candidates, threads);
+    self.rerank(&found, queries, k, metric, threads)
+  }
+
+  /// Re-ranks `candidates` found for `queries` by their exact `metric` similarity using the
+  /// stored vectors, keeping the best `k`. Candidates without a stored vector are dropped.
+  pub fn rerank<S: Copy + Default + Sync>(
+    &self,
+    candidates: &QueryResult<S>,
+    queries: &SVMData,
+    k: usize,
+    metric: Similarity,
+    threads: usize,
+  ) -> QueryResult<f32> {
+    let store = &self.store;
+    rerank_rows(
+      candidates,
+      queries,
+      store.data(),
+      |id| store.row(id),
+      k,
+      metric,
+      threads,
+    )
+  }
+
+  /// Queries the index with the stored vectors of `ids`, which are usually among the results.
+  /// Returns the first ID without a stored vector if there is one.
+  pub fn query_by_id(
+    &self,
+    ids: &[IDType],
+    k: usize,
+    threads: usize,
+  ) -> Result<QueryResult, IDType> {
+    let queries = self.store.fetch(ids)?;
+    Ok(self.query_parallel(&queries, k, threads))
+  }
+
+  /// Returns up to `k` results for each query that collided with it in at least `min_count`
+  /// tables.
+  pub fn query_min_count(
+    &self,
+    data: &SVMData,
+    k: usize,
+    min_count: u32,
+    threads: usize,
+  ) -> QueryResult {
+    let hashes = self.doph.hash(data);
+    self.lsh.query_min_count(&hashes, k, min_count, threads)
+  }
+
+  /// Returns up to `k` results for each query that collided with it in at least `fraction` of the
+  /// tables.
+  pub fn query_min_fraction(
+    &self,
+    data: &SVMData,
+    k: usize,
+    fraction: f64,
+    threads: usize,
+  ) -> QueryResult {
+    let min_count = self.lsh.min_count_for_fraction(fraction);
+    self.query_min_count(data, k, min_count, threads)
+  }
+
+  /// Returns every item whose estimated Jaccard similarity to each query is at least `jaccard`.
+  /// Two sets with Jaccard similarity J collide in a table with probability about p = J^K, where
+  /// K is the number of min-hashes per table, so over L tables they collide L * p times on
+  /// average. Half the items at exactly J collide fewer times than that, so the threshold is two
+  /// standard deviations lower, which keeps about 98% of them at the cost of some candidates below
+  /// J. The threshold is approximate either way.
+  pub fn query_jaccard(&self, data: &SVMData, jaccard: f64, threads: usize) -> QueryResult {
+    let min_count = self.min_count_for_jaccard(jaccard);
+    let hashes = self.doph.hash(data);
+    self.lsh.query_above(&hashes, min_count, threads)
+  }
+
+  /// The number of tables an item with Jaccard similarity `jaccard` to a query is almost certain
+  /// to collide with it in, see `query_jaccard`.
+  fn min_count_for_jaccard(&self, jaccard: f64) -> u32 {
+    let p = jaccard.powi(self.doph.k() as i32).clamp(0.0, 1.0);
+    let tables = self.lsh.tables() as f64;
+    let margin = 2.0 * (tables * p * (1.0 - p)).sqrt();
+    return self
+      .lsh
+      .min_count_for_fraction((tables * p - margin) / tables);
+  }
+
+  /// Removes `id` from the index, along with its key if it was inserted under one. Returns the
+  /// number of buckets it was found in. Panics if the index is frozen.
+  pub fn remove(&mut self, id: IDType) -> usize {
+    let found = self.remove_from_lsh(id);
+    self.store.remove(id);
+    self.labels.remove(id);
+    if let Some(keys) = &mut self.keys {
+      keys.remove_id(id);
+    }
+    return found;
+  }
+
+  /// Removes `id` from the buckets its stored vector hashes to, or by scanning the whole index if
+  /// it has no stored vector. Returns the number of buckets it was found in.
+  fn remove_from_lsh(&mut self, id: IDType) -> usize {
+    match self.store.fetch(&[id]) {
+      Ok(data) => {
+        let hashes = self.doph.hash(&data);
+        self.lsh.remove(id, &hashes)
+      }
+      Err(_) => self.lsh.remove_by_scan(id),
+    }
+  }
+
+  /// Replaces the vector stored under `id` with the single vector in `data`, re-hashing it and
+  /// moving it to its new buckets. Panics if the index is frozen.
+  pub fn update(&mut self, id: IDType, data: &SVMData) {
+    assert_eq!(data.len(), 1, "update expects a single vector");
+    let hashes = self.doph.hash(data);
+    self.remove_from_lsh(id);
+    self.lsh.insert(&[id], &hashes);
+    self.store.insert(id, data, 0);
+  }
+
+  pub fn save(&self, filename: &str) -> io::Result<()> {
+    persist::save_atomic(filename, |e| self.write_to(e))
+  }
+
+  pub fn load(filename: &str) -> io::Result<Index> {
+    persist::load(filename, Index::read_from)
+  }
+
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    e.write_header(INDEX_MAGIC, INDEX_VERSION)?;
+    e.write_checksum()?;
+    self.doph.write_to(e)?;
+    self.lsh.write_to(e)?;
+    self.store.write_to(e)?;
+    e.write_u32(self.keys.is_some() as u32)?;
+    if let Some(keys) = &self.keys {
+      keys.write_to(e)?;
+    }
+    self.labels.write_to(e)
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<Index> {
+    let version = d.read_header(INDEX_MAGIC, INDEX_VERSION)?;
+    d.verify_checksum("index file header")?;
+    let doph = DOPH::read_from(d)?;
+    let lsh = LSH::read_from(d)?;
+    // Version 1 indexes were saved without their vectors.
+    let store = if version >= 2 {
+      VectorStore::read_from(d)?
+    } else {
+      VectorStore::new()
+    };
+    let keys = if version >= 5 {
+      match d.read_u32()? {
+        0 => None,
+        _ => Some(KeyMap::read_from(d)?),
+      }
+    } else if version >= 3 {
+      // Versions 3 and 4 always saved a key map, which started at 0 unless one was given, so an
+      // unused one starting at 0 is taken as not given.
+      let keys = KeyMap::read_from(d)?;
+      if keys.next_id() == 0 {
+        None
+      } else {
+        Some(keys)
+      }
+    } else {
+      None
+    };
+    let labels = if version >= 4 {
+      Labels::read_from(d)?
+    } else {
+      Labels::new()
+    };
+
+    if doph.tables() != lsh.tables() {
+      return Err(invalid_data(format!(
+        "hash functions have {} tables but the index has {}",
+        doph.tables(),
+        lsh.tables()
+      )));
+    }
+
+    Ok(Index {
+      doph,
+      lsh,
+      store,
+      keys,
+      labels,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_data() -> SVMData {
+    SVMData {
+      markers: vec![0, 4, 5, 7, 10, 14],
+      indices: vec![
+        88, 91, 120, 18223, 4, 177, 12, 5, 66, 9001, 88, 91, 120, 18000,
+      ],
+      values: vec![1.0; 14],
+      len: 5,
+    }
+  }
+
+  fn test_index() -> Index {
+    let doph = DOPH::from_seed(8, 2, 6, 3);
+    let mut lsh = LSH::new(8, 6, 4);
+    lsh.insert_range(0, 5, &doph.hash(&test_data()));
+    let mut store = VectorStore::new();
+    store.insert_range(0, &test_data());
+    Index::new(doph, lsh).with_store(store)
+  }
+
+  #[test]
+  fn test_save_load() {
+    let index = test_index();
+
+    let filename = "./temp_index_save_test_file";
+    index.save(filename).expect("index should save");
+    let loaded = Index::load(filename).expect("index should load");
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+
+    let expected = index.query(&test_data(), 5);
+    let result = loaded.query(&test_data(), 5);
+    for q in 0..5 {
+      assert!(result.nth(q).eq(expected.nth(q)));
+      assert!(result.ids(q).any(|id| id == q as IDType));
+    }
+
+    assert_eq!(loaded.store.len(), 5);
+    for id in 0..5 {
+      assert!(loaded
+        .store
+        .get(id)
+        .unwrap()
+        .eq(index.store.get(id).unwrap()));
+    }
+
+    // Version 1 indexes have no stored vectors and no keys.
+    let mut e = Encoder::new(Vec::new());
+    e.write_header(INDEX_MAGIC, 1).unwrap();
+    e.write_checksum().unwrap();
+    index.doph.write_to(&mut e).unwrap();
+    index.lsh.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let old = Index::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+    assert!(old.store.is_empty());
+    assert!(old.keys.is_none());
+    assert!(old.query(&test_data(), 5).nth(2).eq(expected.nth(2)));
+  }
+
+  #[test]
+  fn test_keys() {
+    let doph = DOPH::from_seed(8, 2, 6, 3);
+    let lsh = LSH::new(8, 6, 4);
+    let mut index = Index::new(doph, lsh).with_keys(KeyMap::starting_at(100));
+
+    let keys = ["a", "b", "c", "d", "e"];
+    assert_eq!(
+      index.insert_keyed(&keys, &test_data()),
+      vec![100, 101, 102, 103, 104]
+    );
+
+    let result = index.query_keyed(&test_data(), 5, 2);
+    for q in 0..5 {
+      assert!(result[q].contains(&(keys[q], 8)));
+    }
+
+    // Give "a" the vector of "e".
+    let last = SVMData {
+      markers: vec![0, 4],
+      indices: vec![88, 91, 120, 18000],
+      values: vec![1.0; 4],
+      len: 1,
+    };
+    assert_eq!(index.insert_keyed(&["a"], &last), vec![100]);
+    assert!(index.remove_key("b"));
+    assert!(!index.remove_key("b"));
+
+    let result = index.query_by_key(&["e"], 5, 1).unwrap();
+    assert!(result[0].contains(&("a", 8)));
+    assert!(result[0].contains(&("e", 8)));
+    assert_eq!(index.query_by_key(&["e", "b"], 5, 1).err(), Some("b"));
+
+    let mut e = Encoder::new(Vec::new());
+    index.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let loaded = Index::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+    assert_eq!(loaded.keys.as_ref().unwrap().len(), 4);
+    assert_eq!(loaded.key_id("d"), Some(103));
+    assert_eq!(loaded.query_by_key(&["e"], 5, 1).unwrap(), result);
+
+    // Removing an item by ID forgets its key too.
+    assert_eq!(index.remove(102), 8);
+    assert_eq!(index.key_id("c"), None);
+    assert_eq!(index.query_by_key(&["c"], 5, 1).err(), Some("c"));
+
+    // By default keys start after the items inserted without one, found in the store or the LSH.
+    let mut index = test_index();
+    let first = index.store.fetch(&[0, 1]).unwrap();
+    let second = index.store.fetch(&[1, 0]).unwrap();
+    assert_eq!(index.insert_keyed(&["a", "b"], &second), vec![5, 6]);
+    assert_eq!(index.store.fetch(&[0, 1]).unwrap().indices, first.indices);
+
+    let doph = DOPH::from_seed(8, 2, 6, 3);
+    let mut lsh = LSH::new(8, 6, 4);
+    lsh.insert_range(7, 5, &doph.hash(&test_data()));
+    let mut index = Index::new(doph, lsh);
+    assert_eq!(index.insert_keyed(&["a", "b"], &first), vec![12, 13]);
+
+    // A key map given explicitly is used as is, even one starting at 0, and so is the lack of one
+    // after saving and loading.
+    let mut index = test_index();
+    let mut e = Encoder::new(Vec::new());
+    index.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let mut loaded = Index::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+    assert!(loaded.keys.is_none());
+    assert_eq!(loaded.insert_keyed(&["a"], &last), vec![5]);
+
+    index = index.with_keys(KeyMap::starting_at(0));
+    let mut e = Encoder::new(Vec::new());
+    index.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let mut loaded = Index::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+    assert_eq!(index.insert_keyed(&["a"], &last), vec![0]);
+    assert_eq!(loaded.insert_keyed(&["a"], &last), vec![0]);
+  }
+
+  #[test]
+  fn test_query_by_id() {
+    let mut index = test_index();
+
+    let by_id = index.query_by_id(&[3, 0], 5, 1).unwrap();
+    let by_vector = index.query(&index.store.fetch(&[3, 0]).unwrap(), 5);
+    for q in 0..2 {
+      assert!(by_id.nth(q).eq(by_vector.nth(q)));
+    }
+    assert!(by_id.ids(0).any(|id| id == 3));
+    assert!(by_id.ids(1).any(|id| id == 0));
+
+    index.remove(3);
+    assert_eq!(index.query_by_id(&[0, 3], 5, 1).err(), Some(3));
+  }
+
+  #[test]
+  fn test_remove_update() {
+    let mut index = test_index();
+
+    assert_eq!(index.remove(1), 8);
+    assert_eq!(index.remove(1), 0);
+
+    // Give item 0 the vector of item 4.
+    index.update(
+      0,
+      &SVMData {
+        markers: vec![0, 4],
+        indices: vec![88, 91, 120, 18000],
+        values: vec![1.0; 4],
+        len: 1,
+      },
+    );
+
+    let result = index.query(&test_data(), 5);
+    for q in 0..5 {
+      assert!(result.ids(q).all(|id| id != 1));
+    }
+    let nearest: Vec<IDType> = result.ids(4).collect();
+    assert!(nearest.contains(&0));
+    assert!(nearest.contains(&4));
+
+    assert!(index.store.get(1).is_none());
+    assert_eq!(index.store.get(0).unwrap().count(), 4);
+    assert!(index.store.get(0).unwrap().any(|(i, _)| i == 18000));
+
+    // Items are found by re-hashing their stored vector, or by a scan when there is none.
+    let doph = DOPH::from_seed(8, 2, 6, 3);
+    let mut lsh = LSH::new(8, 6, 4);
+    lsh.insert_range(0, 5, &doph.hash(&test_data()));
+    let mut index = Index::new(doph, lsh);
+    assert_eq!(index.remove(1), 8);
+    assert!(index.query(&test_data(), 5).ids(1).all(|id| id != 1));
+  }
+
+  #[test]
+  fn test_thresholds() {
+    let doph = DOPH::from_seed(8, 2, 6, 3);
+    let mut lsh = LSH::new(8, 6, 4);
+    lsh.insert_range(0, 5, &doph.hash(&test_data()));
+    let index = Index::new(doph, lsh);
+
+    // Only identical sets are certain to collide in every table.
+    let exact = index.query_jaccard(&test_data(), 1.0, 2);
+    for q in 0..5 {
+      assert!(exact
+        .nth(q)
+        .any(|(id, score)| id == q as IDType && score == 8));
+      assert!(exact.nth(q).all(|(_, score)| score == 8));
+    }
+
+    // At J = 0.9 items collide in 0.81 * 8 = 6.48 tables on average, and the margin is about 2.2.
+    assert_eq!(index.min_count_for_jaccard(1.0), 8);
+    assert_eq!(index.min_count_for_jaccard(0.9), 5);
+    assert_eq!(index.min_count_for_jaccard(0.0), 1);
+
+    let loose = index.query_min_fraction(&test_data(), 5, 0.0, 1);
+    let top = index.query(&test_data(), 5);
+    for q in 0..5 {
+      assert!(loose.nth(q).eq(top.nth(q)));
+    }
+
+    let half = index.query_min_count(&test_data(), 5, 4, 1);
+    for q in 0..5 {
+      assert!(half.nth(q).all(|(_, score)| score >= 4));
+    }
+  }
+
+  #[test]
+  fn test_filters() {
+    let mut labels = Labels::new();
+    labels.insert_range(0, &["a", "b", "a", "b", "a"]);
+    let index = test_index().with_labels(labels);
+
+    let mut e = Encoder::new(Vec::new());
+    index.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let index = Index::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+
+    let result = index.query_with_label(&test_data(), 5, 0, "a", 2);
+    for q in 0..5 {
+      assert!(result.ids(q).all(|id| index.labels.get(id) == Some("a")));
+      assert_eq!(result.ids(q).any(|id| id == q as IDType), q % 2 == 0);
+    }
+    let none = index.query_with_label(&test_data(), 5, 1, "c", 1);
+    assert!(none.iter().all(|mut r| r.next().is_none()));
+
+    // A self-join where every query is also in the index.
+    let ids: Vec<IDType> = (0..5).collect();
+    let others = index.query_filtered(&test_data(), 5, 0, &Filter::ExcludeSelf(&ids), 1);
+    let all = index.query(&test_data(), 5);
+    for q in 0..5 {
+      let expected: Vec<_> = all.nth(q).filter(|&(id, _)| id != q as IDType).collect();
+      assert!(others.nth(q).eq(expected));
+    }
+  }
+
+  #[test]
+  fn test_query_reranked() {
+    let index = test_index();
+
+    let result = index.query_reranked(&test_data(), 2, 5, Similarity::Cosine, 2);
+    for q in 0..5 {
+      let (id, sim) = result.nth(q).next().unwrap();
+      assert_eq!(id, q as IDType);
+      assert!((sim - 1.0).abs() < 1e-6);
+      assert!(result.nth(q).all(|(_, s)| s <= sim));
+    }
+  }
+}
@@ -0,0 +1,252 @@
+use crate::lsh::{IDType, QueryResult};
+use crate::persist::{invalid_data, Decoder, Encoder};
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+
+const KEYS_MAGIC: &[u8; 8] = b"FLASHKEY";
+const KEYS_VERSION: u32 = 1;
+
+/// Maps external string keys to the internal IDs stored in the index and back. IDs are assigned
+/// sequentially from `first_id` the first time a key is seen, and are not reused after the key
+/// is removed. 64-bit keys can be mapped through their decimal representation.
+pub struct KeyMap {
+  ids: HashMap<String, IDType>,
+  keys: Vec<Option<String>>,
+  first_id: IDType,
+}
+
+impl Default for KeyMap {
+  fn default() -> Self {
+    KeyMap::new()
+  }
+}
+
+impl KeyMap {
+  pub fn new() -> Self {
+    KeyMap::starting_at(0)
+  }
+
+  /// Creates a map which assigns IDs from `first_id` on, leaving the IDs below it free for items
+  /// inserted without a key.
+  pub fn starting_at(first_id: IDType) -> Self {
+    KeyMap {
+      ids: HashMap::new(),
+      keys: Vec::new(),
+      first_id,
+    }
+  }
+
+  /// Returns the ID of `key`, assigning the next free ID if the key is new.
+  pub fn assign(&mut self, key: &str) -> IDType {
+    if let Some(&id) = self.ids.get(key) {
+      return id;
+    }
+    let offset = self.keys.len() as u64;
+    assert!(
+      self.first_id as u64 + offset < IDType::MAX as u64,
+      "Ran out of IDs to assign to keys"
+    );
+    let id = self.first_id + offset as IDType;
+    self.ids.insert(key.to_string(), id);
+    self.keys.push(Some(key.to_string()));
+    return id;
+  }
+
+  /// The ID the next new key will be assigned.
+  pub fn next_id(&self) -> IDType {
+    self.first_id + self.keys.len() as IDType
+  }
+
+  pub fn id(&self, key: &str) -> Option<IDType> {
+    self.ids.get(key).copied()
+  }
+
+  pub fn key(&self, id: IDType) -> Option<&str> {
+    let offset = id.checked_sub(self.first_id)? as usize;
+    self.keys.get(offset)?.as_deref()
+  }
+
+  /// Forgets `key`, returning the ID it was mapped to.
+  pub fn remove(&mut self, key: &str) -> Option<IDType> {
+    let id = self.ids.remove(key)?;
+    self.keys[(id - self.first_id) as usize] = None;
+    Some(id)
+  }
+
+  /// Forgets the key mapped to `id`, returning whether there was one.
+  pub fn remove_id(&mut self, id: IDType) -> bool {
+    let offset = match id.checked_sub(self.first_id) {
+      Some(offset) => offset as usize,
+      None => return false,
+    };
+    match self.keys.get_mut(offset).and_then(|key| key.take()) {
+      Some(key) => {
+        self.ids.remove(&key);
+        true
+      }
+      None => false,
+    }
+  }
+
+  /// Replaces the IDs in `results` with their keys, dropping IDs without one.
+  pub fn resolve<S: Copy + Default>(&self, results: &QueryResult<S>) -> Vec<Vec<(&str, S)>> {
+    results
+      .iter()
+      .map(|row| {
+        row
+          .filter_map(|(id, score)| Some((self.key(id)?, score)))
+          .collect()
+      })
+      .collect()
+  }
+
+  /// The number of mapped keys.
+  pub fn len(&self) -> usize {
+    self.ids.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.ids.is_empty()
+  }
+
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    let mut lens = Vec::with_capacity(self.keys.len());
+    let mut bytes = Vec::new();
+    for key in &self.keys {
+      // Removed keys are written as a length of u32::MAX so the IDs after them keep their place.
+      match key {
+        Some(key) => {
+          lens.push(key.len() as u32);
+          bytes.extend_from_slice(key.as_bytes());
+        }
+        None => lens.push(u32::MAX),
+      }
+    }
+
+    e.write_header(KEYS_MAGIC, KEYS_VERSION)?;
+    e.write_u32(self.first_id)?;
+    e.write_u64(lens.len() as u64)?;
+    e.write_u64(bytes.len() as u64)?;
+    e.write_checksum()?;
+
+    e.write_u32_slice(&lens)?;
+    e.write_bytes(&bytes)?;
+    e.write_checksum()
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<KeyMap> {
+    d.read_header(KEYS_MAGIC, KEYS_VERSION)?;
+    let first_id = d.read_u32()?;
+    let num_ids = d.read_usize()?;
+    let num_bytes = d.read_usize()?;
+    d.verify_checksum("key map header")?;
+
+    if first_id as u64 + num_ids as u64 > IDType::MAX as u64 {
+      return Err(invalid_data(format!(
+        "key map assigns {} ids from {}, more than there are ids",
+        num_ids, first_id
+      )));
+    }
+
+    let lens = d.read_u32_array(num_ids)?;
+    let total: u64 = lens
+      .iter()
+      .filter(|&&l| l != u32::MAX)
+      .map(|&l| l as u64)
+      .sum();
+    if total != num_bytes as u64 {
+      return Err(invalid_data(format!(
+        "key lengths add up to {} but the key map holds {} bytes",
+        total, num_bytes
+      )));
+    }
+    let mut bytes = vec![0; num_bytes];
+    d.read_bytes(&mut bytes)?;
+    d.verify_checksum("key map keys")?;
+
+    let mut map = KeyMap::starting_at(first_id);
+    let mut start = 0;
+    for (offset, &len) in lens.iter().enumerate() {
+      if len == u32::MAX {
+        map.keys.push(None);
+        continue;
+      }
+      let end = start + len as usize;
+      let key = std::str::from_utf8(&bytes[start..end])
+        .map_err(|_| invalid_data(String::from("key map holds a key that is not UTF-8")))?;
+      start = end;
+
+      let id = first_id + offset as IDType;
+      if map.ids.insert(key.to_string(), id).is_some() {
+        return Err(invalid_data(format!(
+          "key map holds the key '{}' more than once",
+          key
+        )));
+      }
+      map.keys.push(Some(key.to_string()));
+    }
+    Ok(map)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_assign_remove() {
+    let mut keys = KeyMap::starting_at(10);
+    assert_eq!(keys.assign("doc-a"), 10);
+    assert_eq!(keys.assign("doc-b"), 11);
+    assert_eq!(keys.assign("doc-a"), 10);
+    assert_eq!(keys.len(), 2);
+
+    assert_eq!(keys.id("doc-b"), Some(11));
+    assert_eq!(keys.key(10), Some("doc-a"));
+    assert_eq!(keys.key(9), None);
+    assert_eq!(keys.key(12), None);
+
+    assert_eq!(keys.remove("doc-a"), Some(10));
+    assert_eq!(keys.remove("doc-a"), None);
+    assert_eq!(keys.key(10), None);
+    assert_eq!(keys.id("doc-a"), None);
+
+    // Removed IDs are not handed out again.
+    assert_eq!(keys.assign("doc-a"), 12);
+    assert_eq!(keys.len(), 2);
+
+    assert!(keys.remove_id(11));
+    assert!(!keys.remove_id(11));
+    assert!(!keys.remove_id(3));
+    assert_eq!(keys.id("doc-b"), None);
+    assert_eq!(keys.len(), 1);
+  }
+
+  #[test]
+  fn test_write_read() {
+    let mut keys = KeyMap::starting_at(3);
+    for key in &["alpha", "", "18446744073709551615", "ünïcode"] {
+      keys.assign(key);
+    }
+    keys.remove("");
+
+    let mut e = Encoder::new(Vec::new());
+    keys.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let loaded = KeyMap::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+
+    assert_eq!(loaded.len(), 3);
+    for id in 0..8 {
+      assert_eq!(loaded.key(id), keys.key(id));
+    }
+    assert_eq!(loaded.id("ünïcode"), Some(6));
+    assert_eq!(loaded.id(""), None);
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.len() - 9;
+    corrupt[last] ^= 1;
+    assert!(KeyMap::read_from(&mut Decoder::new(&corrupt[..])).is_err());
+  }
+}
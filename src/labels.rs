@@ -0,0 +1,196 @@
+use crate::lsh::IDType;
+use crate::persist::{invalid_data, Decoder, Encoder};
+
+use std::collections::HashMap;
+use std::io;
+use std::io::{Read, Write};
+
+const LABELS_MAGIC: &[u8; 8] = b"FLASHLBL";
+const LABELS_VERSION: u32 = 1;
+
+/// Marks an ID without a label in `Labels::codes`.
+const NO_LABEL: u32 = u32::MAX;
+
+/// Keeps a label for each item in an index so query results can be filtered by it. Each distinct
+/// label is stored once and items refer to it by its position in `names`, so checking an item's
+/// label during a query is a single comparison.
+pub struct Labels {
+  names: Vec<String>,
+  by_name: HashMap<String, u32>,
+  codes: Vec<u32>,
+}
+
+impl Default for Labels {
+  fn default() -> Self {
+    Labels::new()
+  }
+}
+
+impl Labels {
+  pub fn new() -> Self {
+    Labels {
+      names: Vec::new(),
+      by_name: HashMap::new(),
+      codes: Vec::new(),
+    }
+  }
+
+  /// Labels the items `first_id, first_id + 1, ...` with `labels`.
+  pub fn insert_range<L: AsRef<str>>(&mut self, first_id: IDType, labels: &[L]) {
+    for (i, label) in labels.iter().enumerate() {
+      self.set(first_id + i as IDType, label.as_ref());
+    }
+  }
+
+  /// Labels `id` with `label`, replacing any label it had.
+  pub fn set(&mut self, id: IDType, label: &str) {
+    let code = match self.by_name.get(label) {
+      Some(&code) => code,
+      None => {
+        let code = self.names.len() as u32;
+        self.names.push(label.to_string());
+        self.by_name.insert(label.to_string(), code);
+        code
+      }
+    };
+    let id = id as usize;
+    if id >= self.codes.len() {
+      self.codes.resize(id + 1, NO_LABEL);
+    }
+    self.codes[id] = code;
+  }
+
+  /// Removes the label of `id`.
+  pub fn remove(&mut self, id: IDType) {
+    if let Some(code) = self.codes.get_mut(id as usize) {
+      *code = NO_LABEL;
+    }
+  }
+
+  pub fn get(&self, id: IDType) -> Option<&str> {
+    let code = self.code_of(id)?;
+    Some(&self.names[code as usize])
+  }
+
+  /// The code of `label`, which can be compared with `code_of` without looking up strings.
+  pub fn code(&self, label: &str) -> Option<u32> {
+    self.by_name.get(label).copied()
+  }
+
+  pub fn code_of(&self, id: IDType) -> Option<u32> {
+    match self.codes.get(id as usize) {
+      Some(&code) if code != NO_LABEL => Some(code),
+      _ => None,
+    }
+  }
+
+  /// Whether no item has a label.
+  pub fn is_empty(&self) -> bool {
+    self.codes.iter().all(|&code| code == NO_LABEL)
+  }
+
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    let lens: Vec<u32> = self.names.iter().map(|name| name.len() as u32).collect();
+
+    e.write_header(LABELS_MAGIC, LABELS_VERSION)?;
+    e.write_u64(self.names.len() as u64)?;
+    e.write_u64(self.codes.len() as u64)?;
+    e.write_checksum()?;
+
+    e.write_u32_slice(&lens)?;
+    for name in &self.names {
+      e.write_bytes(name.as_bytes())?;
+    }
+    e.write_checksum()?;
+
+    e.write_u32_slice(&self.codes)?;
+    e.write_checksum()
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<Labels> {
+    d.read_header(LABELS_MAGIC, LABELS_VERSION)?;
+    let num_names = d.read_usize()?;
+    let num_ids = d.read_usize()?;
+    d.verify_checksum("labels header")?;
+
+    if num_names >= NO_LABEL as usize || num_ids > IDType::MAX as usize + 1 {
+      return Err(invalid_data(format!(
+        "labels hold {} names for {} ids, more than fit in 32 bits",
+        num_names, num_ids
+      )));
+    }
+
+    let lens = d.read_u32_array(num_names)?;
+    let mut labels = Labels::new();
+    for &len in lens.iter() {
+      let mut bytes = vec![0; len as usize];
+      d.read_bytes(&mut bytes)?;
+      let name = String::from_utf8(bytes)
+        .map_err(|_| invalid_data(String::from("labels hold a name that is not UTF-8")))?;
+      let code = labels.names.len() as u32;
+      if labels.by_name.insert(name.clone(), code).is_some() {
+        return Err(invalid_data(format!(
+          "labels hold the name '{}' more than once",
+          name
+        )));
+      }
+      labels.names.push(name);
+    }
+    d.verify_checksum("label names")?;
+
+    let codes = d.read_u32_array(num_ids)?;
+    d.verify_checksum("item labels")?;
+    if let Some(&code) = codes
+      .iter()
+      .find(|&&code| code != NO_LABEL && code as usize >= num_names)
+    {
+      return Err(invalid_data(format!(
+        "an item has label {} but there are only {} labels",
+        code, num_names
+      )));
+    }
+    labels.codes = codes.to_vec();
+
+    Ok(labels)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_labels() {
+    let mut labels = Labels::new();
+    assert!(labels.is_empty());
+
+    labels.insert_range(2, &["spam", "ham", "spam"]);
+    labels.set(7, "eggs");
+    assert_eq!(labels.get(0), None);
+    assert_eq!(labels.get(2), Some("spam"));
+    assert_eq!(labels.get(3), Some("ham"));
+    assert_eq!(labels.get(7), Some("eggs"));
+    assert_eq!(labels.get(8), None);
+    assert_eq!(labels.code_of(4), labels.code("spam"));
+    assert_eq!(labels.code("toast"), None);
+
+    labels.set(2, "ham");
+    labels.remove(3);
+    assert_eq!(labels.get(2), Some("ham"));
+    assert_eq!(labels.get(3), None);
+
+    let mut e = Encoder::new(Vec::new());
+    labels.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let loaded = Labels::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+    for id in 0..10 {
+      assert_eq!(loaded.get(id), labels.get(id));
+    }
+    assert_eq!(loaded.code("eggs"), labels.code("eggs"));
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.len() - 9;
+    corrupt[last] ^= 1;
+    assert!(Labels::read_from(&mut Decoder::new(&corrupt[..])).is_err());
+  }
+}
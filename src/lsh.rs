@@ -1,300 +1,1753 @@
 use crate::heap_array::HeapAllocatedArray;
+use crate::persist;
+use crate::persist::{invalid_data, Decoder, Encoder};
+
+use rand::rngs::StdRng;
+use rand::{thread_rng, Rng, SeedableRng};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::marker::PhantomData;
+use std::sync::Mutex;
+
+const LSH_MAGIC: &[u8; 8] = b"FLASHLSH";
+const LSH_VERSION: u32 = 4;
+
+const LOCK_STRIPES: usize = 1 << 12;
+
+/// The row of a bucket no item has reached in sparse storage.
+const EMPTY_ROW: [IDType; 1] = [0];
+
+fn mix64(mut x: u64) -> u64 {
+  x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+  x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+  return x ^ (x >> 31);
+}
 
-use rand::{thread_rng, Rng};
-use std::collections::HashMap;
+/// Returns the slot that the item arriving when a bucket already holds `count` items would
+/// occupy in an unbounded reservoir. The result is uniform over [0, count] and is derived by
+/// hashing the seed, bucket and count, so every bucket has its own independent sequence without
+/// any per bucket state.
+fn reservoir_slot(seed: u64, bucket: usize, count: usize) -> usize {
+  let x = mix64(seed ^ mix64(bucket as u64 ^ 0x9e3779b97f4a7c15));
+  let x = mix64(x ^ count as u64);
+  return ((x as u128 * (count as u128 + 1)) >> 64) as usize;
+}
 
 pub type IDType = u32;
 pub type HashType = u32;
 
+/// Decides what happens to a new item when its bucket already holds `reservoir_size` items.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EvictionPolicy {
+  /// Keeps a uniform random sample of every item that reached the bucket.
+  Reservoir,
+  /// Treats the bucket as a ring buffer so it always holds the most recent items.
+  KeepNewest,
+  /// Keeps the first items to reach the bucket and drops the rest.
+  KeepOldest,
+}
+
+impl EvictionPolicy {
+  pub const NAMES: &'static str = "\"reservoir\", \"newest\" or \"oldest\"";
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      EvictionPolicy::Reservoir => "reservoir",
+      EvictionPolicy::KeepNewest => "newest",
+      EvictionPolicy::KeepOldest => "oldest",
+    }
+  }
+
+  fn to_u32(self) -> u32 {
+    match self {
+      EvictionPolicy::Reservoir => 0,
+      EvictionPolicy::KeepNewest => 1,
+      EvictionPolicy::KeepOldest => 2,
+    }
+  }
+
+  fn from_u32(x: u32) -> Option<Self> {
+    match x {
+      0 => Some(EvictionPolicy::Reservoir),
+      1 => Some(EvictionPolicy::KeepNewest),
+      2 => Some(EvictionPolicy::KeepOldest),
+      _ => None,
+    }
+  }
+}
+
+impl std::str::FromStr for EvictionPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "reservoir" => Ok(EvictionPolicy::Reservoir),
+      "newest" => Ok(EvictionPolicy::KeepNewest),
+      "oldest" => Ok(EvictionPolicy::KeepOldest),
+      _ => Err(format!(
+        "unknown eviction policy '{}', expected one of {}",
+        s,
+        EvictionPolicy::NAMES
+      )),
+    }
+  }
+}
+
+/// How an `LSH` stores its buckets.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Storage {
+  /// Preallocates a row for every bucket, which is fastest when most buckets are occupied.
+  Dense,
+  /// Only allocates rows for buckets that items have reached, growing each one up to
+  /// `reservoir_size` items, for large `range_pow` where most buckets stay empty.
+  Sparse,
+  /// Read-only compressed buckets, see `LSH::freeze`.
+  Frozen,
+}
+
+impl Storage {
+  pub const NAMES: &'static str = "\"dense\", \"sparse\" or \"frozen\"";
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Storage::Dense => "dense",
+      Storage::Sparse => "sparse",
+      Storage::Frozen => "frozen",
+    }
+  }
+
+  fn to_u32(self) -> u32 {
+    match self {
+      Storage::Dense => 0,
+      Storage::Sparse => 1,
+      Storage::Frozen => 2,
+    }
+  }
+
+  fn from_u32(x: u32) -> Option<Self> {
+    match x {
+      0 => Some(Storage::Dense),
+      1 => Some(Storage::Sparse),
+      2 => Some(Storage::Frozen),
+      _ => None,
+    }
+  }
+}
+
+impl std::str::FromStr for Storage {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "dense" => Ok(Storage::Dense),
+      "sparse" => Ok(Storage::Sparse),
+      "frozen" => Ok(Storage::Frozen),
+      _ => Err(format!(
+        "unknown storage '{}', expected one of {}",
+        s,
+        Storage::NAMES
+      )),
+    }
+  }
+}
+
+/// The bucket rows of an `LSH`. Each row is the number of items that have reached the bucket
+/// followed by the items it holds.
+enum Buckets {
+  /// Every row of every table in one array, with `reservoir_size` slots per row and unused slots
+  /// set to `IDType::MAX`.
+  Dense(HeapAllocatedArray<IDType>),
+  /// A map per table from hash to row, holding only the occupied buckets. Rows only hold as many
+  /// slots as the bucket has items.
+  Sparse(Vec<HashMap<HashType, Vec<IDType>>>),
+  Frozen(FrozenBuckets),
+}
+
+const FROZEN: &str = "A frozen index cannot be modified";
+const CHECKED: &str = "Frozen buckets are checked when they are loaded";
+
+/// Appends `x` to `out` as a little endian base 128 varint, 7 bits per byte with the high bit
+/// set on every byte but the last.
+fn write_varint(out: &mut Vec<u8>, mut x: u32) {
+  while x >= 0x80 {
+    out.push(x as u8 | 0x80);
+    x >>= 7;
+  }
+  out.push(x as u8);
+}
+
+/// Reads a varint written by `write_varint` starting at `*pos` and moves `pos` past it. Returns
+/// `None` if `bytes` ends before the varint does or it does not fit in a `u32`.
+#[inline]
+fn read_varint(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+  let mut x = 0;
+  let mut shift = 0;
+  loop {
+    let b = *bytes.get(*pos)?;
+    *pos += 1;
+    if shift > 28 || (shift == 28 && b & 0x7f > 0x0f) {
+      return None;
+    }
+    x |= ((b & 0x7f) as u32) << shift;
+    if b < 0x80 {
+      return Some(x);
+    }
+    shift += 7;
+  }
+}
+
+/// Buckets compressed by `LSH::freeze`. Each bucket is its count followed by its items in
+/// ascending order, the first as is and the rest as the difference from the previous item, all
+/// encoded as varints. The buckets of a table are stored back to back starting at
+/// `bases[t]` in `bytes`, and `ends[t * rows + r]` is where bucket `r` ends relative to that, so
+/// an empty bucket takes no bytes besides its end.
+struct FrozenBuckets {
+  bases: Vec<u64>,
+  ends: HeapAllocatedArray<u32>,
+  bytes: Vec<u8>,
+  rows: usize,
+}
+
+impl FrozenBuckets {
+  fn empty(tables: usize, rows: usize) -> Self {
+    FrozenBuckets {
+      bases: vec![0; tables + 1],
+      ends: HeapAllocatedArray::with_default(tables * rows),
+      bytes: Vec::new(),
+      rows,
+    }
+  }
+
+  /// The bytes of bucket `r` of table `t`.
+  fn bucket(&self, t: usize, r: usize) -> &[u8] {
+    let bucket = t * self.rows + r;
+    let start = if r == 0 { 0 } else { self.ends[bucket - 1] };
+    let base = self.bases[t] as usize;
+    &self.bytes[base + start as usize..base + self.ends[bucket] as usize]
+  }
+
+  /// Appends the items of bucket `r` of table `t` to `out` and returns the bucket's count.
+  fn decode(&self, t: usize, r: usize, out: &mut Vec<IDType>) -> u32 {
+    let bytes = self.bucket(t, r);
+    if bytes.is_empty() {
+      return 0;
+    }
+    let mut pos = 0;
+    let count = read_varint(bytes, &mut pos).expect(CHECKED);
+    let mut id = 0;
+    while pos < bytes.len() {
+      id += read_varint(bytes, &mut pos).expect(CHECKED);
+      out.push(id);
+    }
+    return count;
+  }
+
+  /// Walks bucket `r` of table `t` and returns its count and the number of items it holds, or
+  /// `None` if it ends in the middle of a varint, holds one too large for a `u32` or its items do
+  /// not fit in an `IDType`.
+  fn check(&self, t: usize, r: usize) -> Option<(u32, usize)> {
+    let bytes = self.bucket(t, r);
+    if bytes.is_empty() {
+      return Some((0, 0));
+    }
+    let mut pos = 0;
+    let count = read_varint(bytes, &mut pos)?;
+    let mut id: IDType = 0;
+    let mut items = 0;
+    while pos < bytes.len() {
+      id = id.checked_add(read_varint(bytes, &mut pos)?)?;
+      items += 1;
+    }
+    return Some((count, items));
+  }
+}
+
 pub struct LSH {
-  data: HeapAllocatedArray<IDType>,
+  buckets: Buckets,
   tables: usize,
   rows: usize,
   reservoir_size: usize,
   row_size: usize,
   table_size: usize,
-  rand_values: HeapAllocatedArray<usize>,
+  seed: u64,
+  eviction: EvictionPolicy,
 }
 
-pub struct QueryResult {
+/// The results of a batch of queries. Each query has a row of `k + 1` entries in `results`, the
+/// number of results followed by their IDs, and `scores` uses the same layout to hold each
+/// result's score. The first entry of each row of `scores` is unused. Scores are the number of
+/// tables each result collided with the query in, or a similarity once the results have been
+/// re-ranked.
+pub struct QueryResult<S = u32> {
   results: HeapAllocatedArray<IDType>,
+  scores: HeapAllocatedArray<S>,
   len: usize,
   k: usize,
 }
 
-impl QueryResult {
-  fn new(results: HeapAllocatedArray<IDType>, len: usize, k: usize) -> Self {
+impl<S: Copy + Default> QueryResult<S> {
+  fn new(
+    results: HeapAllocatedArray<IDType>,
+    scores: HeapAllocatedArray<S>,
+    len: usize,
+    k: usize,
+  ) -> Self {
     QueryResult {
       results: results,
+      scores: scores,
       len,
       k,
     }
   }
 
-  pub fn nth(&self, idx: usize) -> ResultIter {
+  /// Packs rows of `(id, score)` pairs, one per query, with `k` set to the longest row.
+  pub fn from_rows(rows: &[Vec<(IDType, S)>]) -> Self {
+    let k = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let mut results = HeapAllocatedArray::with_default(rows.len() * (k + 1));
+    let mut scores = HeapAllocatedArray::with_default(rows.len() * (k + 1));
+    for (q, row) in rows.iter().enumerate() {
+      let start = q * (k + 1);
+      results[start] = row.len() as IDType;
+      for (i, &(id, score)) in row.iter().enumerate() {
+        results[start + i + 1] = id;
+        scores[start + i + 1] = score;
+      }
+    }
+
+    QueryResult::new(results, scores, rows.len(), k)
+  }
+
+  /// Returns the `(id, score)` pairs for query `idx`, best first.
+  pub fn nth(&self, idx: usize) -> ResultIter<S> {
     let start = idx * (self.k + 1);
     ResultIter {
       results: &self.results,
+      scores: &self.scores,
       curr: start,
       end: start + (self.results[start] as usize),
     }
   }
 
-  fn len(&self) -> usize {
+  /// Returns just the IDs for query `idx`, best first.
+  pub fn ids(&self, idx: usize) -> impl Iterator<Item = IDType> + '_ {
+    self.nth(idx).map(|(id, _)| id)
+  }
+
+  /// Iterates over the results of every query in order.
+  pub fn iter(&self) -> QueryIter<'_, S> {
+    QueryIter {
+      result: self,
+      curr: 0,
+    }
+  }
+
+  /// The number of queries.
+  pub fn len(&self) -> usize {
     self.len
   }
 
-  fn count(&self, idx: usize) -> usize {
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// The number of results for query `idx`, which is at most `k`.
+  pub fn count(&self, idx: usize) -> usize {
     self.results[idx * (self.k + 1)] as usize
   }
+
+  /// Applies `f` to every score.
+  pub fn map_scores<T: Copy + Default, F: Fn(S) -> T>(self, f: F) -> QueryResult<T> {
+    let mut scores = HeapAllocatedArray::with_default(self.scores.len());
+    for (to, &from) in scores.iter_mut().zip(self.scores.iter()) {
+      *to = f(from);
+    }
+    QueryResult::new(self.results, scores, self.len, self.k)
+  }
+}
+
+impl<S: Copy + Default + std::fmt::Display> QueryResult<S> {
+  /// Writes the results as text, one line per query of space separated `id:score` pairs.
+  pub fn save(&self, filename: &str) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(filename)?);
+    for row in self.iter() {
+      for (i, (id, score)) in row.enumerate() {
+        if i != 0 {
+          write!(writer, " ")?;
+        }
+        write!(writer, "{}:{}", id, score)?;
+      }
+      writeln!(writer)?;
+    }
+    writer.flush()
+  }
+}
+
+impl<S: Copy + Default + std::str::FromStr> QueryResult<S> {
+  /// Reads results written by `save`. A result without a `:score` suffix gets the default score.
+  pub fn load(filename: &str) -> io::Result<Self> {
+    let reader = BufReader::new(File::open(filename)?);
+
+    let mut rows: Vec<Vec<(IDType, S)>> = Vec::new();
+    for line in reader.lines() {
+      let line = line?;
+      let mut row = Vec::new();
+      for tok in line.split_whitespace() {
+        let invalid = || {
+          io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid result '{}' on line {}", tok, rows.len() + 1),
+          )
+        };
+        let (id, score) = match tok.split_once(':') {
+          Some((id, score)) => (id, score.parse::<S>().map_err(|_| invalid())?),
+          None => (tok, S::default()),
+        };
+        let id = id.parse::<IDType>().map_err(|_| invalid())?;
+        row.push((id, score));
+      }
+      rows.push(row);
+    }
+
+    Ok(QueryResult::from_rows(&rows))
+  }
+}
+
+impl<'a, S: Copy + Default> IntoIterator for &'a QueryResult<S> {
+  type Item = ResultIter<'a, S>;
+  type IntoIter = QueryIter<'a, S>;
+
+  fn into_iter(self) -> Self::IntoIter {
+    self.iter()
+  }
 }
 
-pub struct ResultIter<'a> {
+pub struct QueryIter<'a, S = u32> {
+  result: &'a QueryResult<S>,
+  curr: usize,
+}
+
+impl<'a, S: Copy + Default> Iterator for QueryIter<'a, S> {
+  type Item = ResultIter<'a, S>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.curr >= self.result.len {
+      return None;
+    }
+    self.curr += 1;
+    return Some(self.result.nth(self.curr - 1));
+  }
+}
+
+pub struct ResultIter<'a, S = u32> {
   results: &'a HeapAllocatedArray<IDType>,
+  scores: &'a HeapAllocatedArray<S>,
   curr: usize,
   end: usize,
 }
 
-impl<'a> Iterator for ResultIter<'a> {
-  type Item = IDType;
+impl<'a, S: Copy> Iterator for ResultIter<'a, S> {
+  type Item = (IDType, S);
 
   fn next(&mut self) -> Option<Self::Item> {
     if self.curr >= self.end {
       return None;
     }
     self.curr += 1;
-    return Some(self.results[self.curr]);
+    return Some((self.results[self.curr], self.scores[self.curr]));
   }
 }
 
-impl LSH {
-  pub fn new(tables: usize, range_pow: usize, reservoir_size: usize) -> Self {
-    let mut rng = thread_rng();
-    let mut rand_values = HeapAllocatedArray::new(reservoir_size * 20);
-    for i in 1..reservoir_size * 20 {
-      rand_values[i] = rng.gen::<usize>() % i;
-    }
+/// Returns how many threads to answer `num_query` queries with, given that at most `threads` are
+/// wanted, and how many consecutive queries each thread answers.
+fn split_queries(num_query: usize, threads: usize) -> (usize, usize) {
+  let threads = std::cmp::max(1, std::cmp::min(threads, num_query));
+  let per_thread = std::cmp::max(1, num_query.div_ceil(threads));
+  return (threads, per_thread);
+}
 
-    let rows = 1 << range_pow;
-    let mut lsh = LSH {
-      data: HeapAllocatedArray::with_value(tables * rows * (reservoir_size + 1), IDType::MAX),
-      tables,
-      rows: rows,
-      reservoir_size,
-      row_size: reservoir_size + 1,
-      table_size: rows * (reservoir_size + 1),
-      rand_values,
-    };
+/// The sort key ranking a candidate and its count: higher counts first, then lower IDs.
+fn rank(&(id, cnt): &(IDType, u32)) -> (std::cmp::Reverse<u32>, IDType) {
+  (std::cmp::Reverse(cnt), id)
+}
 
-    for t in 0..tables {
-      for r in 0..rows {
-        lsh.data[t * lsh.table_size + r * lsh.row_size] = 0;
-      }
-    }
+/// Restricts which items a filtered query may return. Filters are applied to the counted
+/// candidates before the best `k` are selected, so a query still returns `k` results whenever
+/// at least `k` of its candidates pass.
+pub enum Filter<'a> {
+  /// Keeps the items for which the predicate returns true.
+  Predicate(&'a (dyn Fn(IDType) -> bool + Sync)),
+  /// Keeps only the listed items.
+  Allow(&'a HashSet<IDType>),
+  /// Leaves out the listed items.
+  Deny(&'a HashSet<IDType>),
+  /// Leaves out the item each query was made from, for self-joins where the queries are items in
+  /// the index. `ids[q]` is the ID of query `q`.
+  ExcludeSelf(&'a [IDType]),
+  /// Keeps the items that pass every filter.
+  All(Vec<Filter<'a>>),
+}
 
-    return lsh;
+impl Filter<'_> {
+  /// Whether query `q` may return `id`.
+  pub fn keeps(&self, q: usize, id: IDType) -> bool {
+    match self {
+      Filter::Predicate(f) => f(id),
+      Filter::Allow(ids) => ids.contains(&id),
+      Filter::Deny(ids) => !ids.contains(&id),
+      Filter::ExcludeSelf(ids) => ids[q] != id,
+      Filter::All(filters) => filters.iter().all(|f| f.keeps(q, id)),
+    }
   }
 
-  pub fn insert(&mut self, ids: &[IDType], hashes: &[HashType]) {
-    for n in 0..ids.len() {
-      let id = ids[n];
+  /// Panics if the filter does not fit a batch of `num_query` queries.
+  fn check_queries(&self, num_query: usize) {
+    match self {
+      Filter::ExcludeSelf(ids) => assert_eq!(
+        ids.len(),
+        num_query,
+        "ExcludeSelf should have one ID for each query"
+      ),
+      Filter::All(filters) => filters.iter().for_each(|f| f.check_queries(num_query)),
+      _ => {}
+    }
+  }
+}
 
-      for t in 0..self.tables {
-        let hash = hashes[n * self.tables + t] as usize;
-        let offset = t * self.table_size + hash * self.row_size;
-        let count = self.data[offset] as usize;
+/// Buffers reused across the queries answered by one thread: the IDs found in the query's
+/// buckets and the number of times each distinct ID was found.
+struct QueryScratch {
+  candidates: Vec<IDType>,
+  counts: Vec<(IDType, u32)>,
+}
 
-        self.data[offset] += 1;
-        if count < self.reservoir_size {
-          self.data[offset + count + 1] = id;
-        } else {
-          let r = self.rand_values[count];
-          if r < self.reservoir_size {
-            self.data[offset + 1 + r] = id;
-          }
-        }
-      }
+impl QueryScratch {
+  fn new(capacity: usize) -> Self {
+    QueryScratch {
+      candidates: Vec::with_capacity(capacity),
+      counts: Vec::with_capacity(capacity),
     }
   }
+}
 
-  pub fn insert_range(&mut self, id_start: IDType, count: usize, hashes: &[HashType]) {
-    for n in 0..count {
-      let id = id_start + n as IDType;
+/// The parameters needed to add an item to a single bucket row, where `row[0]` is the count of
+/// items that have reached the bucket and the remaining `reservoir_size` slots hold the items.
+#[derive(Clone, Copy)]
+struct RowInserter {
+  reservoir_size: usize,
+  seed: u64,
+  eviction: EvictionPolicy,
+}
 
-      for t in 0..self.tables {
-        let hash = hashes[n * self.tables + t] as usize;
-        let offset = t * self.table_size + hash * self.row_size;
-        let count = self.data[offset] as usize;
+impl RowInserter {
+  /// Adds `id` to the row for the bucket at `offset`, applying the eviction policy once it is
+  /// full. With reservoir sampling the item replaces a random slot with probability
+  /// reservoir_size / (count + 1), so every item that reached the bucket is equally likely to be
+  /// kept no matter how many arrive.
+  fn insert(&self, row: &mut [IDType], offset: usize, id: IDType) {
+    let count = row[0] as usize;
+
+    row[0] = row[0].saturating_add(1);
+    if count < self.reservoir_size {
+      row[count + 1] = id;
+      return;
+    }
 
-        self.data[offset] += 1;
-        if count < self.reservoir_size {
-          self.data[offset + count + 1] = id;
-        } else {
-          let r = self.rand_values[count];
-          if r < self.reservoir_size {
-            self.data[offset + 1 + r] = id;
-          }
-        }
-      }
+    let r = match self.eviction {
+      EvictionPolicy::Reservoir => reservoir_slot(self.seed, offset, count),
+      EvictionPolicy::KeepNewest => count % self.reservoir_size,
+      EvictionPolicy::KeepOldest => return,
+    };
+    if r < self.reservoir_size {
+      row[1 + r] = id;
     }
   }
 
-  pub fn query(&self, hashes: &[HashType], k: usize) -> QueryResult {
-    let num_query = hashes.len() / self.tables;
-    let mut result: HeapAllocatedArray<IDType> =
-      HeapAllocatedArray::with_default(num_query * (k + 1));
-
-    let mut counts: HashMap<IDType, u32> =
-      HashMap::with_capacity(self.reservoir_size * self.tables);
+  /// Same as `insert` for a row that only holds as many slots as its bucket has items.
+  fn insert_growing(&self, row: &mut Vec<IDType>, offset: usize, id: IDType) {
+    if (row[0] as usize) < self.reservoir_size {
+      row.push(IDType::MAX);
+    }
+    self.insert(row, offset, id);
+  }
 
-    for q in 0..num_query {
-      for t in 0..self.tables {
-        let hash = hashes[q * self.tables + t] as usize;
-        let offset = t * self.table_size + hash * self.row_size;
-        let count = std::cmp::min(self.data[offset] as usize, self.reservoir_size);
-        for i in 1..count + 1 {
-          let id = self.data[offset + i];
-          match counts.get(&id) {
-            Some(&cnt) => {
-              counts.insert(id, cnt + 1);
-            }
-            None => {
-              counts.insert(id, 1);
-            }
-          }
-        }
-      }
+  /// Removes every copy of `id` from `row`, shifting the remaining items down so the occupied
+  /// slots stay contiguous, and returns the number of items left or `None` if `id` was not in
+  /// the row. The count is reset to the number of items left, since the bucket now has room and
+  /// the next insert should fill the freed slot rather than be sampled against the items that
+  /// were dropped earlier. The bucket's overflow is lost with it, and later reservoir sampling
+  /// treats the remaining items as the whole stream, so it favours items that arrive afterwards.
+  fn remove(&self, row: &mut [IDType], id: IDType) -> Option<usize> {
+    let count = row[0] as usize;
+    let stored = std::cmp::min(count, self.reservoir_size);
+
+    if !row[1..stored + 1].contains(&id) {
+      return None;
+    }
 
-      let mut topk: Vec<_> = counts.drain().collect();
-      topk.sort_by(|a, b| b.1.cmp(&a.1));
+    // A full ring buffer starts at its oldest item, so rotate it into insertion order first to
+    // keep the newest items last once the count is reset.
+    if self.eviction == EvictionPolicy::KeepNewest && count > self.reservoir_size {
+      row[1..stored + 1].rotate_left(count % self.reservoir_size);
+    }
 
-      let start = (k + 1) * q;
-      let num = std::cmp::min(topk.len(), k);
-      result[start] = num as IDType;
-      for i in 0..num {
-        result[start + i + 1] = topk[i].0;
+    let mut kept = 0;
+    for i in 1..stored + 1 {
+      let x = row[i];
+      if x != id {
+        kept += 1;
+        row[kept] = x;
       }
     }
+    for x in row[kept + 1..stored + 1].iter_mut() {
+      *x = IDType::MAX;
+    }
+    row[0] = kept as IDType;
+
+    return Some(kept);
+  }
+}
+
+/// Inserts into an `LSH` from several threads at once. With dense storage each bucket is guarded
+/// by one of a fixed set of striped locks, so threads only contend when they hit buckets sharing
+/// a stripe, while with sparse storage each table's map has its own lock. Bucket counts always
+/// match a sequential build, while which items a full bucket keeps depends on the order the
+/// threads reach it in.
+pub struct ConcurrentInserter<'a> {
+  target: ConcurrentBuckets<'a>,
+  tables: usize,
+  rows: usize,
+  row_size: usize,
+  table_size: usize,
+  inserter: RowInserter,
+}
+
+enum ConcurrentBuckets<'a> {
+  Dense {
+    data: *mut IDType,
+    locks: Vec<Mutex<()>>,
+    _lsh: PhantomData<&'a mut LSH>,
+  },
+  Sparse(Vec<Mutex<&'a mut HashMap<HashType, Vec<IDType>>>>),
+}
 
-    return QueryResult::new(result, num_query, k);
+// Every access to a dense row through `data` happens while holding the lock for that row's
+// stripe, and the borrow of the index keeps any other access out for the inserter's lifetime.
+unsafe impl Send for ConcurrentInserter<'_> {}
+unsafe impl Sync for ConcurrentInserter<'_> {}
+
+impl<'a> ConcurrentInserter<'a> {
+  pub fn insert(&self, ids: &[IDType], hashes: &[HashType]) {
+    for n in 0..ids.len() {
+      self.insert_one(ids[n], &hashes[n * self.tables..(n + 1) * self.tables]);
+    }
   }
 
-  fn override_rand_values(&mut self, vals: &[usize]) {
-    for i in 0..self.reservoir_size * 20 {
-      self.rand_values[i] = vals[i];
+  pub fn insert_range(&self, id_start: IDType, count: usize, hashes: &[HashType]) {
+    for n in 0..count {
+      self.insert_one(
+        id_start + n as IDType,
+        &hashes[n * self.tables..(n + 1) * self.tables],
+      );
     }
   }
-}
 
-impl std::fmt::Display for LSH {
-  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+  fn insert_one(&self, id: IDType, hashes: &[HashType]) {
     for t in 0..self.tables {
-      write!(f, "Table: {}\n", t)?;
-      for r in 0..self.rows {
-        let start = t * self.table_size + r * self.row_size;
-        write!(f, "    Row {}[{}]: ", r, self.data[start])?;
-        for i in 1..(std::cmp::min(self.data[start] as usize, self.reservoir_size) + 1) {
-          write!(f, "{} ", self.data[start + i])?;
+      let hash = hashes[t] as usize;
+      assert!(hash < self.rows, "Hash {} is out of range", hash);
+      let offset = t * self.table_size + hash * self.row_size;
+
+      match &self.target {
+        ConcurrentBuckets::Dense { data, locks, .. } => {
+          let bucket = t * self.rows + hash;
+          let _guard = locks[bucket % locks.len()]
+            .lock()
+            .expect("Insert lock should not be poisoned");
+          let row = unsafe { std::slice::from_raw_parts_mut(data.add(offset), self.row_size) };
+          self.inserter.insert(row, offset, id);
+        }
+        ConcurrentBuckets::Sparse(maps) => {
+          let mut map = maps[t].lock().expect("Insert lock should not be poisoned");
+          let row = map.entry(hash as HashType).or_insert_with(|| vec![0]);
+          self.inserter.insert_growing(row, offset, id);
         }
-        write!(f, "\n")?;
       }
-      write!(f, "\n")?;
     }
-
-    Ok(())
   }
 }
 
-#[cfg(test)]
-mod tests {
-  use super::*;
-
-  #[test]
-  fn test_query_result() {
-    let data = [3, 8, 9, 2, 0, 0, 1, 1, 1, 1, 4, 90, 91, 92, 93];
-    let mut arr = HeapAllocatedArray::new(15);
-    for i in 0..15 {
-      arr[i] = data[i];
-    }
-    let res = QueryResult::new(arr, 3, 4);
+impl LSH {
+  pub fn new(tables: usize, range_pow: usize, reservoir_size: usize) -> Self {
+    LSH::with_rng(tables, range_pow, reservoir_size, &mut thread_rng())
+  }
 
-    let ex1 = vec![8, 9, 2];
-    for (i, x) in res.nth(0).enumerate() {
-      assert_eq!(ex1[i], x);
-    }
-    assert_eq!(res.count(0), 3);
+  /// Creates an index whose reservoir sampling decisions are the same every time it is called
+  /// with the same arguments.
+  pub fn from_seed(tables: usize, range_pow: usize, reservoir_size: usize, seed: u64) -> Self {
+    LSH::with_rng(
+      tables,
+      range_pow,
+      reservoir_size,
+      &mut StdRng::seed_from_u64(seed),
+    )
+  }
 
-    let ex2: Vec<IDType> = vec![];
-    for (i, x) in res.nth(1).enumerate() {
-      assert_eq!(ex2[i], x);
-    }
-    assert_eq!(res.count(1), 0);
+  pub fn with_rng<R: Rng>(
+    tables: usize,
+    range_pow: usize,
+    reservoir_size: usize,
+    rng: &mut R,
+  ) -> Self {
+    LSH::build(tables, range_pow, reservoir_size, false, rng)
+  }
 
-    let ex3 = vec![90, 91, 92, 93];
-    for (i, x) in res.nth(2).enumerate() {
-      assert_eq!(ex3[i], x);
+  /// Creates an index that stores its buckets as given by `storage`. The reservoir sampling
+  /// decisions do not depend on the storage, so an index built the same way with either storage
+  /// holds the same items. A frozen index cannot be inserted into, so it is built with another
+  /// storage and then compressed with `freeze`, and asking for frozen storage is an error.
+  pub fn with_storage<R: Rng>(
+    tables: usize,
+    range_pow: usize,
+    reservoir_size: usize,
+    storage: Storage,
+    rng: &mut R,
+  ) -> Result<Self, String> {
+    match storage {
+      Storage::Dense => Ok(LSH::build(tables, range_pow, reservoir_size, false, rng)),
+      Storage::Sparse => Ok(LSH::build(tables, range_pow, reservoir_size, true, rng)),
+      Storage::Frozen => Err(String::from(
+        "a frozen index is built with dense or sparse storage and then frozen",
+      )),
     }
-    assert_eq!(res.count(2), 4);
   }
 
-  fn do_simple_insert() -> LSH {
-    let ids = [1, 2, 3, 4];
-    let hashes = [0, 0, 1, 3, 2, 1, 0, 2, 3, 0, 0, 3, 2, 3, 0, 3];
-
-    let mut lsh = LSH::new(4, 2, 4);
-
-    lsh.insert(&ids, &hashes);
+  /// Creates an empty index with sparse or dense storage.
+  fn build<R: Rng>(
+    tables: usize,
+    range_pow: usize,
+    reservoir_size: usize,
+    sparse: bool,
+    rng: &mut R,
+  ) -> Self {
+    let rows = 1 << range_pow;
+    let row_size = reservoir_size + 1;
+    let buckets = if sparse {
+      Buckets::Sparse((0..tables).map(|_| HashMap::new()).collect())
+    } else {
+      let mut data = HeapAllocatedArray::with_value(tables * rows * row_size, IDType::MAX);
+      for bucket in 0..tables * rows {
+        data[bucket * row_size] = 0;
+      }
+      Buckets::Dense(data)
+    };
 
-    return lsh;
+    return LSH {
+      buckets,
+      tables,
+      rows: rows,
+      reservoir_size,
+      row_size,
+      table_size: rows * row_size,
+      seed: rng.gen(),
+      eviction: EvictionPolicy::Reservoir,
+    };
   }
 
-  fn do_second_insert(lsh: &mut LSH) {
-    let mut new_rands = [15; 80];
-
-    new_rands[5] = 2;
+  /// Sets the policy used for buckets that are already full. Buckets that filled up under a
+  /// previous policy are not reordered.
+  pub fn with_eviction(mut self, eviction: EvictionPolicy) -> Self {
+    self.eviction = eviction;
+    return self;
+  }
 
-    lsh.override_rand_values(&new_rands);
+  pub fn eviction(&self) -> EvictionPolicy {
+    self.eviction
+  }
 
-    let ids = [5, 6, 7];
-    let hashes = [2, 1, 0, 1, 0, 2, 0, 3, 2, 3, 0, 3];
+  pub fn tables(&self) -> usize {
+    self.tables
+  }
 
-    lsh.insert(&ids, &hashes);
+  /// The number of buckets in each table, `2^range_pow`.
+  pub fn rows(&self) -> usize {
+    self.rows
   }
 
-  #[test]
-  fn test_simple_insert() {
-    let lsh = do_simple_insert();
+  pub fn reservoir_size(&self) -> usize {
+    self.reservoir_size
+  }
 
-    let xx = IDType::MAX;
+  /// Whether the index has been frozen, after which it can only be queried.
+  pub fn is_frozen(&self) -> bool {
+    matches!(self.buckets, Buckets::Frozen(_))
+  }
 
-    let expected = [
-      1, 1, xx, xx, xx, 0, xx, xx, xx, xx, 2, 2, 4, xx, xx, 1, 3, xx, xx, xx, 2, 1, 3, xx, xx, 1,
-      2, xx, xx, xx, 0, xx, xx, xx, xx, 1, 4, xx, xx, xx, 3, 2, 3, 4, xx, 1, 1, xx, xx, xx, 0, xx,
-      xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 2, xx, xx, xx, 3, 1,
-      3, 4, xx,
-    ];
+  pub fn storage(&self) -> Storage {
+    match self.buckets {
+      Buckets::Dense(_) => Storage::Dense,
+      Buckets::Sparse(_) => Storage::Sparse,
+      Buckets::Frozen(_) => Storage::Frozen,
+    }
+  }
 
-    for i in 0..80 {
-      assert_eq!(lsh.data[i], expected[i]);
+  /// The approximate number of bytes of memory taken by the buckets.
+  pub fn memory_bytes(&self) -> usize {
+    let id = std::mem::size_of::<IDType>();
+    match &self.buckets {
+      Buckets::Dense(data) => data.len() * id,
+      Buckets::Sparse(maps) => maps
+        .iter()
+        .map(|map| {
+          // Each map slot holds a hash, a row and a control byte, and each row owns its slots.
+          let slot = std::mem::size_of::<(HashType, Vec<IDType>)>() + 1;
+          map.capacity() * slot + map.values().map(|row| row.capacity() * id).sum::<usize>()
+        })
+        .sum(),
+      Buckets::Frozen(frozen) => {
+        frozen.bases.len() * std::mem::size_of::<u64>()
+          + frozen.ends.len() * std::mem::size_of::<u32>()
+          + frozen.bytes.len()
+      }
     }
   }
 
-  #[test]
-  fn test_reservoir_overflow() {
-    let mut lsh = do_simple_insert();
+  /// Sorts and compresses every bucket, which makes the index read-only: inserting into or
+  /// removing from it afterwards panics, see `is_frozen`. Queries return the same results as
+  /// before.
+  pub fn freeze(&mut self) {
+    if let Buckets::Frozen(_) = self.buckets {
+      return;
+    }
 
-    do_second_insert(&mut lsh);
+    let mut frozen = FrozenBuckets::empty(self.tables, self.rows);
+    let mut items = Vec::with_capacity(self.reservoir_size);
+    for t in 0..self.tables {
+      let base = frozen.bytes.len();
+      for r in 0..self.rows {
+        let row = self.row(t, r);
+        let count = row[0];
+        if count > 0 {
+          items.clear();
+          items.extend_from_slice(&row[1..std::cmp::min(count as usize, self.reservoir_size) + 1]);
+          items.sort_unstable();
+          write_varint(&mut frozen.bytes, count);
+          let mut prev = 0;
+          for &id in &items {
+            write_varint(&mut frozen.bytes, id - prev);
+            prev = id;
+          }
+        }
+        let end = frozen.bytes.len() - base;
+        assert!(
+          end <= u32::MAX as usize,
+          "Table {} is too large to freeze",
+          t
+        );
+        frozen.ends[t * self.rows + r] = end as u32;
+      }
+      frozen.bases[t + 1] = frozen.bytes.len() as u64;
+    }
+    frozen.bytes.shrink_to_fit();
+    self.buckets = Buckets::Frozen(frozen);
+  }
+
+  /// The position of the row for `hash` in table `t` within dense storage. Reservoir sampling is
+  /// seeded with it under either storage so both make the same decisions.
+  fn offset(&self, t: usize, hash: usize) -> usize {
+    t * self.table_size + hash * self.row_size
+  }
+
+  /// The row for `hash` in table `t`: the number of items that reached the bucket followed by at
+  /// least `min(count, reservoir_size)` slots holding its items.
+  fn row(&self, t: usize, hash: usize) -> &[IDType] {
+    match &self.buckets {
+      Buckets::Dense(data) => {
+        let offset = self.offset(t, hash);
+        &(**data)[offset..offset + self.row_size]
+      }
+      Buckets::Sparse(maps) => maps[t]
+        .get(&(hash as HashType))
+        .map_or(&EMPTY_ROW[..], |row| &row[..]),
+      Buckets::Frozen(_) => panic!("Frozen buckets are compressed and have no rows"),
+    }
+  }
+
+  /// Appends the items held by the bucket for `hash` in table `t` to `out` and returns the
+  /// number of items that reached the bucket.
+  fn read_bucket(&self, t: usize, hash: usize, out: &mut Vec<IDType>) -> u32 {
+    match &self.buckets {
+      Buckets::Frozen(frozen) => frozen.decode(t, hash, out),
+      _ => {
+        let row = self.row(t, hash);
+        let stored = std::cmp::min(row[0] as usize, self.reservoir_size);
+        out.extend_from_slice(&row[1..stored + 1]);
+        row[0]
+      }
+    }
+  }
+
+  /// The largest ID held by any bucket, found by scanning the whole index.
+  pub fn max_id(&self) -> Option<IDType> {
+    let mut ids = Vec::new();
+    let mut max = None;
+    for t in 0..self.tables {
+      for r in 0..self.rows {
+        ids.clear();
+        self.read_bucket(t, r, &mut ids);
+        max = std::cmp::max(max, ids.iter().copied().max());
+      }
+    }
+    return max;
+  }
+
+  /// Calls `f` with the number of items that reached each non-empty bucket of table `t`, without
+  /// reading the items themselves.
+  pub fn for_each_count<F: FnMut(u32)>(&self, t: usize, mut f: F) {
+    match &self.buckets {
+      Buckets::Dense(data) => {
+        for r in 0..self.rows {
+          let count = data[self.offset(t, r)];
+          if count > 0 {
+            f(count);
+          }
+        }
+      }
+      Buckets::Sparse(maps) => maps[t].values().for_each(|row| f(row[0])),
+      Buckets::Frozen(frozen) => {
+        for r in 0..self.rows {
+          let bytes = frozen.bucket(t, r);
+          if !bytes.is_empty() {
+            f(read_varint(bytes, &mut 0).expect(CHECKED));
+          }
+        }
+      }
+    }
+  }
+
+  /// Inserts `ids`, where `hashes` holds one hash per table for each of them. Panics if the index
+  /// is frozen, see `is_frozen`.
+  pub fn insert(&mut self, ids: &[IDType], hashes: &[HashType]) {
+    for n in 0..ids.len() {
+      for t in 0..self.tables {
+        let hash = hashes[n * self.tables + t] as usize;
+        self.insert_into_bucket(t, hash, ids[n]);
+      }
+    }
+  }
+
+  /// Inserts the `count` consecutive IDs starting at `id_start`. Panics if the index is frozen.
+  pub fn insert_range(&mut self, id_start: IDType, count: usize, hashes: &[HashType]) {
+    for n in 0..count {
+      let id = id_start + n as IDType;
+
+      for t in 0..self.tables {
+        let hash = hashes[n * self.tables + t] as usize;
+        self.insert_into_bucket(t, hash, id);
+      }
+    }
+  }
+
+  /// Same as `insert_range` but spreads the tables across `threads` threads. Each table is a
+  /// disjoint region of the index and sees the items in the same order as a serial build, so the
+  /// result is identical to `insert_range`. Panics if the index is frozen.
+  pub fn insert_range_parallel(
+    &mut self,
+    id_start: IDType,
+    count: usize,
+    hashes: &[HashType],
+    threads: usize,
+  ) {
+    let threads = std::cmp::max(1, std::cmp::min(threads, self.tables));
+    let tables_per_thread = self.tables.div_ceil(threads);
+    let (tables, rows, table_size, row_size) =
+      (self.tables, self.rows, self.table_size, self.row_size);
+    let inserter = self.row_inserter();
+
+    std::thread::scope(|scope| match &mut self.buckets {
+      Buckets::Dense(data) => {
+        for (i, chunk) in data.chunks_mut(tables_per_thread * table_size).enumerate() {
+          scope.spawn(move || {
+            let first_table = i * tables_per_thread;
+            for (j, table) in chunk.chunks_mut(table_size).enumerate() {
+              let t = first_table + j;
+              for n in 0..count {
+                let id = id_start + n as IDType;
+                let row = hashes[n * tables + t] as usize * row_size;
+                inserter.insert(&mut table[row..row + row_size], t * table_size + row, id);
+              }
+            }
+          });
+        }
+      }
+      Buckets::Sparse(maps) => {
+        for (i, chunk) in maps.chunks_mut(tables_per_thread).enumerate() {
+          scope.spawn(move || {
+            let first_table = i * tables_per_thread;
+            for (j, map) in chunk.iter_mut().enumerate() {
+              let t = first_table + j;
+              for n in 0..count {
+                let id = id_start + n as IDType;
+                let hash = hashes[n * tables + t];
+                assert!((hash as usize) < rows, "Hash {} is out of range", hash);
+                let row = map.entry(hash).or_insert_with(|| vec![0]);
+                let offset = t * table_size + hash as usize * row_size;
+                inserter.insert_growing(row, offset, id);
+              }
+            }
+          });
+        }
+      }
+      Buckets::Frozen(_) => panic!("{}", FROZEN),
+    });
+  }
+
+  fn insert_into_bucket(&mut self, t: usize, hash: usize, id: IDType) {
+    let inserter = self.row_inserter();
+    let offset = self.offset(t, hash);
+    match &mut self.buckets {
+      Buckets::Dense(data) => {
+        inserter.insert(&mut (**data)[offset..offset + self.row_size], offset, id)
+      }
+      Buckets::Sparse(maps) => {
+        assert!(hash < self.rows, "Hash {} is out of range", hash);
+        let row = maps[t].entry(hash as HashType).or_insert_with(|| vec![0]);
+        inserter.insert_growing(row, offset, id);
+      }
+      Buckets::Frozen(_) => panic!("{}", FROZEN),
+    }
+  }
+
+  fn row_inserter(&self) -> RowInserter {
+    RowInserter {
+      reservoir_size: self.reservoir_size,
+      seed: self.seed,
+      eviction: self.eviction,
+    }
+  }
+
+  /// Returns a handle that several threads can insert through at once. It borrows the index
+  /// mutably, so no queries can run until every inserting thread is done with it. Panics if the
+  /// index is frozen.
+  pub fn concurrent_inserter(&mut self) -> ConcurrentInserter<'_> {
+    let buckets = self.tables * self.rows;
+    let stripes = std::cmp::min(buckets, LOCK_STRIPES);
+    let inserter = self.row_inserter();
+    let target = match &mut self.buckets {
+      Buckets::Dense(data) => ConcurrentBuckets::Dense {
+        data: data.as_mut_ptr(),
+        locks: (0..stripes).map(|_| Mutex::new(())).collect(),
+        _lsh: PhantomData,
+      },
+      Buckets::Sparse(maps) => ConcurrentBuckets::Sparse(maps.iter_mut().map(Mutex::new).collect()),
+      Buckets::Frozen(_) => panic!("{}", FROZEN),
+    };
+    ConcurrentInserter {
+      target,
+      tables: self.tables,
+      rows: self.rows,
+      row_size: self.row_size,
+      table_size: self.table_size,
+      inserter,
+    }
+  }
+
+  /// Removes `id` from the buckets given by its hashes, one per table. Returns the number of
+  /// tables it was found in. Those buckets then count only the items they still hold, see
+  /// `RowInserter::remove`. Panics if the index is frozen.
+  pub fn remove(&mut self, id: IDType, hashes: &[HashType]) -> usize {
+    assert_eq!(
+      hashes.len(),
+      self.tables,
+      "Removing an item takes one hash per table"
+    );
+    let mut found = 0;
+    for t in 0..self.tables {
+      if self.remove_from_bucket(t, hashes[t] as usize, id) {
+        found += 1;
+      }
+    }
+    return found;
+  }
+
+  /// Removes `id` from every bucket it occupies by scanning the whole index, for when its hashes
+  /// are no longer known. Returns the number of buckets it was found in. Panics if the index is
+  /// frozen.
+  pub fn remove_by_scan(&mut self, id: IDType) -> usize {
+    let inserter = self.row_inserter();
+    let mut found = 0;
+    match &mut self.buckets {
+      Buckets::Dense(data) => {
+        for row in data.chunks_mut(self.row_size) {
+          if inserter.remove(row, id).is_some() {
+            found += 1;
+          }
+        }
+      }
+      Buckets::Sparse(maps) => {
+        for map in maps.iter_mut() {
+          map.retain(|_, row| match inserter.remove(row, id) {
+            Some(kept) => {
+              found += 1;
+              row.truncate(kept + 1);
+              kept > 0
+            }
+            None => true,
+          });
+        }
+      }
+      Buckets::Frozen(_) => panic!("{}", FROZEN),
+    }
+    return found;
+  }
+
+  /// Moves `id` from the buckets given by `old_hashes` to those given by `new_hashes`. Panics if
+  /// the index is frozen.
+  pub fn update(&mut self, id: IDType, old_hashes: &[HashType], new_hashes: &[HashType]) {
+    self.remove(id, old_hashes);
+    self.insert(&[id], new_hashes);
+  }
+
+  /// Removes every copy of `id` from the bucket for `hash` in table `t`. Sparse rows shrink to
+  /// the items left and are dropped once empty.
+  fn remove_from_bucket(&mut self, t: usize, hash: usize, id: IDType) -> bool {
+    let inserter = self.row_inserter();
+    let offset = self.offset(t, hash);
+    match &mut self.buckets {
+      Buckets::Dense(data) => inserter
+        .remove(&mut (**data)[offset..offset + self.row_size], id)
+        .is_some(),
+      Buckets::Sparse(maps) => {
+        let row = match maps[t].get_mut(&(hash as HashType)) {
+          Some(row) => row,
+          None => return false,
+        };
+        match inserter.remove(row, id) {
+          Some(0) => {
+            maps[t].remove(&(hash as HashType));
+            true
+          }
+          Some(kept) => {
+            row.truncate(kept + 1);
+            true
+          }
+          None => false,
+        }
+      }
+      Buckets::Frozen(_) => panic!("{}", FROZEN),
+    }
+  }
+
+  pub fn query(&self, hashes: &[HashType], k: usize) -> QueryResult {
+    self.query_min_count(hashes, k, 1, 1)
+  }
+
+  /// Same as `query` but splits the queries across `threads` threads. Each thread has its own
+  /// candidate counts and writes a disjoint range of the results.
+  pub fn query_parallel(&self, hashes: &[HashType], k: usize, threads: usize) -> QueryResult {
+    self.query_min_count(hashes, k, 1, threads)
+  }
+
+  /// Returns up to `k` results for each query, leaving out candidates that collided with the
+  /// query in fewer than `min_count` tables.
+  pub fn query_min_count(
+    &self,
+    hashes: &[HashType],
+    k: usize,
+    min_count: u32,
+    threads: usize,
+  ) -> QueryResult {
+    self.query_batch(hashes, 0, k, min_count, None, threads)
+  }
+
+  /// Multi-probe version of `query_parallel`, where `hashes` holds `probes + 1` hashes for each
+  /// table of each query, as returned by `DOPH::hash_with_probes`. Every distinct bucket among a
+  /// table's hashes is visited, and a result's score is the number of tables it was found in.
+  pub fn query_multiprobe(
+    &self,
+    hashes: &[HashType],
+    probes: usize,
+    k: usize,
+    threads: usize,
+  ) -> QueryResult {
+    self.query_batch(hashes, probes, k, 1, None, threads)
+  }
+
+  /// Multi-probe query which only returns the items that pass `filter`, see `Filter`. Pass
+  /// `probes = 0` with the hashes from `DOPH::hash` for a plain query.
+  pub fn query_filtered(
+    &self,
+    hashes: &[HashType],
+    probes: usize,
+    k: usize,
+    filter: &Filter,
+    threads: usize,
+  ) -> QueryResult {
+    filter.check_queries(hashes.len() / (self.tables * (probes + 1)));
+    self.query_batch(hashes, probes, k, 1, Some(filter), threads)
+  }
+
+  fn query_batch(
+    &self,
+    hashes: &[HashType],
+    probes: usize,
+    k: usize,
+    min_count: u32,
+    filter: Option<&Filter>,
+    threads: usize,
+  ) -> QueryResult {
+    let per_query = self.tables * (probes + 1);
+    let num_query = hashes.len() / per_query;
+    let mut result: HeapAllocatedArray<IDType> =
+      HeapAllocatedArray::with_default(num_query * (k + 1));
+    let mut scores: HeapAllocatedArray<u32> = HeapAllocatedArray::with_default(num_query * (k + 1));
+
+    let (threads, per_thread) = split_queries(num_query, threads);
+    let chunk_len = per_thread * (k + 1);
+
+    std::thread::scope(|scope| {
+      let chunks = result
+        .chunks_mut(chunk_len)
+        .zip(scores.chunks_mut(chunk_len))
+        .enumerate();
+      for (i, (id_chunk, score_chunk)) in chunks {
+        let first = i * per_thread;
+        let mut work = move || {
+          let mut scratch = QueryScratch::new(self.tables * self.reservoir_size);
+          let rows = id_chunk
+            .chunks_mut(k + 1)
+            .zip(score_chunk.chunks_mut(k + 1));
+          for (j, (ids, row_scores)) in rows.enumerate() {
+            let q = first + j;
+            let hashes = &hashes[q * per_query..(q + 1) * per_query];
+            let keep = filter.map(|f| move |id| f.keeps(q, id));
+            let keep = keep.as_ref().map(|f| f as &dyn Fn(IDType) -> bool);
+            let best = self.rank_candidates(hashes, probes, &mut scratch, k, min_count, keep);
+            ids[0] = best.len() as IDType;
+            for (i, &(id, score)) in best.iter().enumerate() {
+              ids[i + 1] = id;
+              row_scores[i + 1] = score;
+            }
+          }
+        };
+        if threads == 1 {
+          work();
+        } else {
+          scope.spawn(work);
+        }
+      }
+    });
+
+    return QueryResult::new(result, scores, num_query, k);
+  }
+
+  /// Returns every candidate that collided with the query in at least `min_count` tables, however
+  /// many there are, ranked as in `query`.
+  pub fn query_above(&self, hashes: &[HashType], min_count: u32, threads: usize) -> QueryResult {
+    let num_query = hashes.len() / self.tables;
+    let (threads, per_thread) = split_queries(num_query, threads);
+
+    let run = |first: usize| {
+      let mut scratch = QueryScratch::new(self.tables * self.reservoir_size);
+      let last = std::cmp::min(first + per_thread, num_query);
+      (first..last)
+        .map(|q| {
+          let hashes = &hashes[q * self.tables..(q + 1) * self.tables];
+          self
+            .rank_candidates(hashes, 0, &mut scratch, usize::MAX, min_count, None)
+            .to_vec()
+        })
+        .collect::<Vec<_>>()
+    };
+
+    let rows: Vec<Vec<(IDType, u32)>> = if threads == 1 {
+      run(0)
+    } else {
+      std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..num_query)
+          .step_by(per_thread)
+          .map(|first| scope.spawn(move || run(first)))
+          .collect();
+        handles
+          .into_iter()
+          .flat_map(|h| h.join().expect("query thread should not panic"))
+          .collect()
+      })
+    };
+
+    return QueryResult::from_rows(&rows);
+  }
+
+  /// The smallest number of tables that is at least `fraction` of all tables, and at least 1.
+  pub fn min_count_for_fraction(&self, fraction: f64) -> u32 {
+    let count = (fraction * self.tables as f64 - 1e-9).ceil();
+    return count.clamp(1.0, self.tables as f64) as u32;
+  }
+
+  /// Ranks the candidates for a single query by how many tables they collide with it in and
+  /// returns the best `k` of those found in at least `min_count` tables, best first, paired with
+  /// their counts. `hashes` holds `probes + 1` buckets to visit in each table, and when `keep` is
+  /// given only the candidates for which it returns true are ranked.
+  ///
+  /// The bucket contents are gathered into one buffer and sorted so that repeated IDs are adjacent
+  /// and can be counted in a single pass, then only the best `k` of the counts are selected and
+  /// sorted. This avoids hashing every candidate and sorting every distinct candidate. Candidates
+  /// with equal counts are ranked by ID so the results only depend on the index and the query.
+  fn rank_candidates<'s>(
+    &self,
+    hashes: &[HashType],
+    probes: usize,
+    scratch: &'s mut QueryScratch,
+    k: usize,
+    min_count: u32,
+    keep: Option<&dyn Fn(IDType) -> bool>,
+  ) -> &'s [(IDType, u32)] {
+    let candidates = &mut scratch.candidates;
+    candidates.clear();
+    for (t, buckets) in hashes.chunks(probes + 1).enumerate() {
+      for (j, &hash) in buckets.iter().enumerate() {
+        // Each item is in one bucket per table, so only repeated buckets could count it twice.
+        if buckets[..j].contains(&hash) {
+          continue;
+        }
+        self.read_bucket(t, hash as usize, candidates);
+      }
+    }
+    candidates.sort_unstable();
+
+    let counts = &mut scratch.counts;
+    counts.clear();
+    for &id in candidates.iter() {
+      match counts.last_mut() {
+        Some((last, cnt)) if *last == id => *cnt += 1,
+        _ => counts.push((id, 1)),
+      }
+    }
+    if min_count > 1 {
+      counts.retain(|&(_, cnt)| cnt >= min_count);
+    }
+    if let Some(keep) = keep {
+      counts.retain(|&(id, _)| keep(id));
+    }
+
+    let num = std::cmp::min(counts.len(), k);
+    if num == 0 {
+      return &[];
+    }
+    if num < counts.len() {
+      counts.select_nth_unstable_by_key(num - 1, rank);
+    }
+    let topk = &mut counts[..num];
+    topk.sort_unstable_by_key(rank);
+    return topk;
+  }
+
+  /// Saves the index to `filename`. The file is written to a temporary file first and renamed
+  /// into place, so an existing index is never left half overwritten.
+  pub fn save(&self, filename: &str) -> io::Result<()> {
+    persist::save_atomic(filename, |e| self.write_to(e))
+  }
+
+  pub fn load(filename: &str) -> io::Result<LSH> {
+    persist::load(filename, LSH::read_from)
+  }
+
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    e.write_header(LSH_MAGIC, LSH_VERSION)?;
+    e.write_u64(self.tables as u64)?;
+    e.write_u64(self.rows.trailing_zeros() as u64)?;
+    e.write_u64(self.reservoir_size as u64)?;
+    e.write_u64(self.seed)?;
+    e.write_u32(self.eviction.to_u32())?;
+    e.write_u32(self.storage().to_u32())?;
+    e.write_checksum()?;
+
+    match &self.buckets {
+      Buckets::Dense(data) => e.write_u32_slice(data)?,
+      Buckets::Sparse(maps) => {
+        // Each table's occupied buckets are written in hash order as the number of buckets, then
+        // their hashes, then the lengths of their rows and finally the rows themselves.
+        for map in maps {
+          let mut hashes: Vec<HashType> = map.keys().copied().collect();
+          hashes.sort_unstable();
+          let lens: Vec<u32> = hashes.iter().map(|h| map[h].len() as u32).collect();
+          e.write_u64(hashes.len() as u64)?;
+          e.write_u32_slice(&hashes)?;
+          e.write_u32_slice(&lens)?;
+          for h in &hashes {
+            e.write_u32_slice(&map[h])?;
+          }
+        }
+      }
+      Buckets::Frozen(frozen) => {
+        e.write_u64_slice(&frozen.bases)?;
+        e.write_u32_slice(&frozen.ends)?;
+        e.write_bytes(&frozen.bytes)?;
+      }
+    }
+    e.write_checksum()
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<LSH> {
+    let version = d.read_header(LSH_MAGIC, LSH_VERSION)?;
+    if version < 2 {
+      return Err(invalid_data(format!(
+        "index format version {} used a bounded reservoir and is no longer supported, \
+         the index must be rebuilt",
+        version
+      )));
+    }
+    let tables = d.read_usize()?;
+    let range_pow = d.read_usize()?;
+    let reservoir_size = d.read_usize()?;
+    let seed = d.read_u64()?;
+    let eviction = if version >= 3 {
+      let x = d.read_u32()?;
+      EvictionPolicy::from_u32(x)
+        .ok_or_else(|| invalid_data(format!("unknown eviction policy {}", x)))?
+    } else {
+      EvictionPolicy::Reservoir
+    };
+    let storage = if version >= 4 {
+      let x = d.read_u32()?;
+      Storage::from_u32(x).ok_or_else(|| invalid_data(format!("unknown storage {}", x)))?
+    } else {
+      Storage::Dense
+    };
+    d.verify_checksum("index header")?;
+
+    let dims = if range_pow < 32 {
+      reservoir_size
+        .checked_add(1)
+        .and_then(|row_size| row_size.checked_mul(1 << range_pow))
+        .and_then(|table_size| Some((table_size, table_size.checked_mul(tables)?)))
+    } else {
+      None
+    };
+    let (table_size, len) = dims.ok_or_else(|| {
+      invalid_data(format!(
+        "invalid index dimensions: {} tables, range_pow {}, reservoir_size {}",
+        tables, range_pow, reservoir_size
+      ))
+    })?;
+
+    let rows = 1 << range_pow;
+    let buckets = match storage {
+      Storage::Dense => Buckets::Dense(d.read_u32_array(len)?),
+      Storage::Sparse => {
+        let mut maps = Vec::with_capacity(tables);
+        for _ in 0..tables {
+          let num_buckets = d.read_usize()?;
+          if num_buckets > rows {
+            return Err(invalid_data(format!(
+              "index table holds {} buckets but only has {}",
+              num_buckets, rows
+            )));
+          }
+          let hashes = d.read_u32_array(num_buckets)?;
+          let lens = d.read_u32_array(num_buckets)?;
+          let mut map = HashMap::with_capacity(num_buckets);
+          for (&hash, &len) in hashes.iter().zip(lens.iter()) {
+            if hash as usize >= rows || len < 2 || len as usize > reservoir_size + 1 {
+              return Err(invalid_data(format!(
+                "invalid bucket {} of length {} in sparse index",
+                hash, len
+              )));
+            }
+            let row = d.read_u32_array(len as usize)?.to_vec();
+            if std::cmp::min(row[0] as usize, reservoir_size) != row.len() - 1 {
+              return Err(invalid_data(format!(
+                "bucket {} holds {} items but has a count of {}",
+                hash,
+                row.len() - 1,
+                row[0]
+              )));
+            }
+            if map.insert(hash, row).is_some() {
+              return Err(invalid_data(format!(
+                "bucket {} appears twice in sparse index",
+                hash
+              )));
+            }
+          }
+          maps.push(map);
+        }
+        Buckets::Sparse(maps)
+      }
+      Storage::Frozen => {
+        let bases = d.read_u64_array(tables + 1)?.to_vec();
+        let ends = d.read_u32_array(tables * rows)?;
+        for t in 0..tables {
+          let table = &(*ends)[t * rows..(t + 1) * rows];
+          let size = bases[t + 1].checked_sub(bases[t]);
+          if table.windows(2).any(|w| w[0] > w[1])
+            || size != Some(table.last().map_or(0, |&end| end as u64))
+          {
+            return Err(invalid_data(format!(
+              "bucket offsets of table {} in frozen index are inconsistent",
+              t
+            )));
+          }
+        }
+        if bases[0] != 0 || bases[tables] > usize::MAX as u64 {
+          return Err(invalid_data(String::from(
+            "frozen index has invalid table offsets",
+          )));
+        }
+        let mut bytes = vec![0; bases[tables] as usize];
+        d.read_bytes(&mut bytes)?;
+        let frozen = FrozenBuckets {
+          bases,
+          ends,
+          bytes,
+          rows,
+        };
+        for t in 0..tables {
+          for r in 0..rows {
+            match frozen.check(t, r) {
+              Some((count, items)) if std::cmp::min(count as usize, reservoir_size) == items => {}
+              _ => {
+                return Err(invalid_data(format!(
+                  "bucket {} of table {} in frozen index is corrupt",
+                  r, t
+                )))
+              }
+            }
+          }
+        }
+        Buckets::Frozen(frozen)
+      }
+    };
+    d.verify_checksum("index data")?;
+
+    Ok(LSH {
+      buckets,
+      tables,
+      rows,
+      reservoir_size,
+      row_size: reservoir_size + 1,
+      table_size,
+      seed,
+      eviction,
+    })
+  }
+}
+
+impl std::fmt::Display for LSH {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    for t in 0..self.tables {
+      write!(f, "Table: {}\n", t)?;
+      let mut items = Vec::with_capacity(self.reservoir_size);
+      for r in 0..self.rows {
+        items.clear();
+        let count = self.read_bucket(t, r, &mut items);
+        write!(f, "    Row {}[{}]: ", r, count)?;
+        for id in &items {
+          write!(f, "{} ", id)?;
+        }
+        write!(f, "\n")?;
+      }
+      write!(f, "\n")?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// The bucket rows of an index with dense storage.
+  fn dense(lsh: &LSH) -> &HeapAllocatedArray<IDType> {
+    match &lsh.buckets {
+      Buckets::Dense(data) => data,
+      _ => panic!("expected dense storage"),
+    }
+  }
+
+  fn test_result() -> QueryResult {
+    let data = [3, 8, 9, 2, 0, 0, 1, 1, 1, 1, 4, 90, 91, 92, 93];
+    let score_data = [0, 5, 3, 3, 0, 0, 0, 0, 0, 0, 0, 9, 4, 2, 1];
+    let mut arr = HeapAllocatedArray::new(15);
+    let mut scores = HeapAllocatedArray::new(15);
+    for i in 0..15 {
+      arr[i] = data[i];
+      scores[i] = score_data[i];
+    }
+    QueryResult::new(arr, scores, 3, 4)
+  }
+
+  #[test]
+  fn test_query_result() {
+    let res = test_result();
+
+    assert_eq!(res.len(), 3);
+    assert_eq!(res.nth(0).collect::<Vec<_>>(), vec![(8, 5), (9, 3), (2, 3)]);
+    assert_eq!(res.count(0), 3);
+
+    assert_eq!(res.nth(1).count(), 0);
+    assert_eq!(res.count(1), 0);
+
+    assert_eq!(res.ids(2).collect::<Vec<_>>(), vec![90, 91, 92, 93]);
+    assert_eq!(res.count(2), 4);
+
+    let counts: Vec<usize> = res.iter().map(|r| r.count()).collect();
+    assert_eq!(counts, vec![3, 0, 4]);
+    let mut best = Vec::new();
+    for mut row in &res {
+      best.push(row.next());
+    }
+    assert_eq!(best, vec![Some((8, 5)), None, Some((90, 9))]);
+  }
+
+  #[test]
+  fn test_query_result_save_load() {
+    let res = test_result();
+
+    let filename = "./temp_query_result_test_file";
+    res.save(filename).expect("results should save");
+    let loaded = QueryResult::load(filename).expect("results should load");
+
+    assert_eq!(loaded.len(), 3);
+    for q in 0..3 {
+      assert_eq!(loaded.count(q), res.count(q));
+      assert!(loaded.nth(q).eq(res.nth(q)));
+    }
+
+    // Files with IDs only are still accepted, with scores of zero.
+    std::fs::write(filename, "4 7\n\n1\n").unwrap();
+    let loaded = QueryResult::load(filename).expect("results should load");
+    assert_eq!(loaded.nth(0).collect::<Vec<_>>(), vec![(4, 0), (7, 0)]);
+    assert_eq!(loaded.count(1), 0);
+
+    std::fs::write(filename, "").unwrap();
+    let loaded = QueryResult::<u32>::load(filename).expect("results should load");
+    assert_eq!(loaded.len(), 0);
+
+    std::fs::write(filename, "4:x 7\n").unwrap();
+    assert!(QueryResult::<u32>::load(filename).is_err());
+
+    let similarities = test_result().map_scores(|s| s as f32 / 10.0);
+    similarities.save(filename).expect("results should save");
+    let loaded = QueryResult::<f32>::load(filename).expect("results should load");
+    assert_eq!(
+      loaded.nth(2).collect::<Vec<_>>(),
+      vec![(90, 0.9), (91, 0.4), (92, 0.2), (93, 0.1)]
+    );
+
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+  }
+
+  const FIRST_IDS: [IDType; 4] = [1, 2, 3, 4];
+  const FIRST_HASHES: [HashType; 16] = [0, 0, 1, 3, 2, 1, 0, 2, 3, 0, 0, 3, 2, 3, 0, 3];
+  const SECOND_IDS: [IDType; 3] = [5, 6, 7];
+  const SECOND_HASHES: [HashType; 12] = [2, 1, 0, 1, 0, 2, 0, 3, 2, 3, 0, 3];
+
+  fn do_simple_insert() -> LSH {
+    let mut lsh = LSH::from_seed(4, 2, 4, 1);
+
+    lsh.insert(&FIRST_IDS, &FIRST_HASHES);
+
+    return lsh;
+  }
+
+  fn do_second_insert(lsh: &mut LSH) {
+    lsh.insert(&SECOND_IDS, &SECOND_HASHES);
+  }
+
+  #[test]
+  fn test_simple_insert() {
+    let lsh = do_simple_insert();
+
+    let xx = IDType::MAX;
+
+    let expected = [
+      1, 1, xx, xx, xx, 0, xx, xx, xx, xx, 2, 2, 4, xx, xx, 1, 3, xx, xx, xx, 2, 1, 3, xx, xx, 1,
+      2, xx, xx, xx, 0, xx, xx, xx, xx, 1, 4, xx, xx, xx, 3, 2, 3, 4, xx, 1, 1, xx, xx, xx, 0, xx,
+      xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 2, xx, xx, xx, 3, 1,
+      3, 4, xx,
+    ];
+
+    for i in 0..80 {
+      assert_eq!(dense(&lsh)[i], expected[i]);
+    }
+  }
+
+  #[test]
+  fn test_reservoir_overflow() {
+    let mut lsh = do_simple_insert();
+
+    do_second_insert(&mut lsh);
 
     let xx = IDType::MAX;
 
+    // Six items reach row 0 of table 2 and five reach row 3 of table 3, so both sample which
+    // items to keep.
     let expected = [
       2, 1, 6, xx, xx, 0, xx, xx, xx, xx, 4, 2, 4, 5, 7, 1, 3, xx, xx, xx, 2, 1, 3, xx, xx, 2, 2,
-      5, xx, xx, 1, 6, xx, xx, xx, 2, 4, 7, xx, xx, 6, 2, 3, 7, 5, 1, 1, xx, xx, xx, 0, xx, xx, xx,
-      xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 5, xx, xx, xx, 1, 2, xx, xx, xx, 5, 1, 3, 4, 6,
+      5, xx, xx, 1, 6, xx, xx, xx, 2, 4, 7, xx, xx, 6, 7, 3, 4, 5, 1, 1, xx, xx, xx, 0, xx, xx, xx,
+      xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 5, xx, xx, xx, 1, 2, xx, xx, xx, 5, 1, 3, 7, 6,
     ];
 
     for i in 0..80 {
-      assert_eq!(lsh.data[i], expected[i]);
+      assert_eq!(dense(&lsh)[i], expected[i]);
+    }
+
+    // The sampled rows are the ones `reservoir_slot` dictates: the first four items fill the
+    // row, and each later one replaces the slot drawn for its arrival if that slot is in range.
+    let ids: Vec<IDType> = FIRST_IDS.iter().chain(SECOND_IDS.iter()).copied().collect();
+    let hashes: Vec<HashType> = FIRST_HASHES
+      .iter()
+      .chain(SECOND_HASHES.iter())
+      .copied()
+      .collect();
+    for &(t, r) in &[(2, 0), (3, 3)] {
+      let mut row = Vec::new();
+      let mut count = 0;
+      for n in 0..ids.len() {
+        if hashes[n * 4 + t] as usize != r {
+          continue;
+        }
+        if count < 4 {
+          row.push(ids[n]);
+        } else {
+          let slot = reservoir_slot(lsh.seed, lsh.offset(t, r), count);
+          if slot < 4 {
+            row[slot] = ids[n];
+          }
+        }
+        count += 1;
+      }
+      assert_eq!(lsh.row(t, r)[1..], row[..]);
+    }
+
+    // Which items are kept depends on the seed, but every bucket counts all the items that
+    // reached it and keeps as many of them as fit, each at most once.
+    for seed in 0..50 {
+      let mut lsh = LSH::from_seed(4, 2, 4, seed);
+      lsh.insert(&ids, &hashes);
+      for t in 0..4 {
+        for r in 0..4 {
+          let reached: Vec<IDType> = (0..ids.len())
+            .filter(|&n| hashes[n * 4 + t] as usize == r)
+            .map(|n| ids[n])
+            .collect();
+          let row = lsh.row(t, r);
+          let stored = std::cmp::min(reached.len(), 4);
+          assert_eq!(row[0] as usize, reached.len());
+          if reached.len() <= 4 {
+            assert_eq!(row[1..stored + 1], reached[..]);
+          }
+
+          let mut kept = row[1..stored + 1].to_vec();
+          assert!(kept.iter().all(|id| reached.contains(id)));
+          kept.sort_unstable();
+          kept.dedup();
+          assert_eq!(kept.len(), stored);
+        }
+      }
     }
   }
 
@@ -309,24 +1762,857 @@ mod tests {
     let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
     let result = lsh.query(&hashes, 4);
 
-    let result1: Vec<IDType> = result.nth(0).collect();
-    let match1 = result1[0] == 6
-      && result1[1] == 1
-      && ((result1[2] == 4 && result1[3] == 3) || (result1[2] == 3 && result1[3] == 4));
-    assert!(match1);
+    // Row 3 of table 3 sampled 7 in place of 4, see `test_reservoir_overflow`.
+    let result1: Vec<IDType> = result.ids(0).collect();
+    assert_eq!(result1, vec![6, 1, 3, 7]);
     assert_eq!(result.count(0), 4);
 
-    let result2: Vec<IDType> = result.nth(1).collect();
-    let match2 = result2[0] == 5 && result2[1] == 2;
-    assert!(match2);
+    let scores: Vec<Vec<u32>> = result
+      .iter()
+      .map(|r| r.map(|(_, score)| score).collect())
+      .collect();
+    assert_eq!(scores, vec![vec![3, 2, 1, 1], vec![2, 1], vec![1]]);
+
+    let result2: Vec<IDType> = result.ids(1).collect();
+    assert_eq!(result2, vec![5, 2]);
     assert_eq!(result.count(1), 2);
 
-    let result3: Vec<IDType> = result.nth(2).collect();
-    let match3 = result3[0] == 6;
-    assert!(match3);
+    let result3: Vec<IDType> = result.ids(2).collect();
+    assert_eq!(result3, vec![6]);
     assert_eq!(result.count(2), 1);
   }
 
+  #[test]
+  fn test_query_thresholds() {
+    let mut lsh = do_simple_insert();
+    do_second_insert(&mut lsh);
+
+    let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
+    let rows = |result: QueryResult| -> Vec<Vec<(IDType, u32)>> {
+      result.iter().map(|r| r.collect()).collect()
+    };
+
+    assert_eq!(
+      rows(lsh.query_min_count(&hashes, 4, 2, 1)),
+      vec![vec![(6, 3), (1, 2)], vec![(5, 2)], vec![]]
+    );
+    assert_eq!(
+      rows(lsh.query_min_count(&hashes, 1, 2, 2)),
+      vec![vec![(6, 3)], vec![(5, 2)], vec![]]
+    );
+
+    let all = lsh.query_above(&hashes, 1, 1);
+    assert_eq!(
+      rows(all),
+      vec![
+        vec![(6, 3), (1, 2), (3, 1), (7, 1)],
+        vec![(5, 2), (2, 1)],
+        vec![(6, 1)]
+      ]
+    );
+    assert_eq!(
+      rows(lsh.query_above(&hashes, 3, 3)),
+      vec![vec![(6, 3)], vec![], vec![]]
+    );
+
+    assert_eq!(lsh.min_count_for_fraction(0.0), 1);
+    assert_eq!(lsh.min_count_for_fraction(0.3), 2);
+    assert_eq!(lsh.min_count_for_fraction(0.5), 2);
+    assert_eq!(lsh.min_count_for_fraction(1.0), 4);
+    assert_eq!(lsh.min_count_for_fraction(1.5), 4);
+  }
+
+  #[test]
+  fn test_query_filtered() {
+    let mut lsh = do_simple_insert();
+    do_second_insert(&mut lsh);
+
+    let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
+    let rows = |filter: &Filter, threads: usize| -> Vec<Vec<(IDType, u32)>> {
+      let result = lsh.query_filtered(&hashes, 0, 2, filter, threads);
+      result.iter().map(|r| r.collect()).collect()
+    };
+
+    // Unfiltered the results are [(6, 3), (1, 2), (3, 1), (7, 1)], [(5, 2), (2, 1)] and [(6, 1)].
+    let six: HashSet<IDType> = vec![6].into_iter().collect();
+    assert_eq!(
+      rows(&Filter::Deny(&six), 1),
+      vec![vec![(1, 2), (3, 1)], vec![(5, 2), (2, 1)], vec![]]
+    );
+
+    let allowed: HashSet<IDType> = vec![3, 5, 7].into_iter().collect();
+    assert_eq!(
+      rows(&Filter::Allow(&allowed), 2),
+      vec![vec![(3, 1), (7, 1)], vec![(5, 2)], vec![]]
+    );
+
+    let query_ids = [1, 5, 7];
+    assert_eq!(
+      rows(&Filter::ExcludeSelf(&query_ids), 3),
+      vec![vec![(6, 3), (3, 1)], vec![(2, 1)], vec![(6, 1)]]
+    );
+
+    let small = |id: IDType| id < 5;
+    assert_eq!(
+      rows(&Filter::Predicate(&small), 1),
+      vec![vec![(1, 2), (3, 1)], vec![(2, 1)], vec![]]
+    );
+
+    let both = Filter::All(vec![Filter::Deny(&six), Filter::ExcludeSelf(&query_ids)]);
+    assert_eq!(
+      rows(&both, 2),
+      vec![vec![(3, 1), (7, 1)], vec![(2, 1)], vec![]]
+    );
+  }
+
+  #[test]
+  fn test_save_load() {
+    let mut lsh = do_simple_insert();
+    do_second_insert(&mut lsh);
+
+    let filename = "./temp_lsh_save_test_file";
+    lsh.save(filename).expect("index should save");
+    let loaded = LSH::load(filename).expect("index should load");
+
+    assert_eq!(loaded.tables, lsh.tables);
+    assert_eq!(loaded.rows, lsh.rows);
+    assert_eq!(loaded.reservoir_size, lsh.reservoir_size);
+    assert_eq!(**dense(&loaded), **dense(&lsh));
+    assert_eq!(loaded.seed, lsh.seed);
+    assert_eq!(loaded.eviction, lsh.eviction);
+
+    let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
+    let expected = lsh.query(&hashes, 4);
+    let result = loaded.query(&hashes, 4);
+    for q in 0..3 {
+      assert!(result.nth(q).eq(expected.nth(q)));
+    }
+
+    let mut bytes = std::fs::read(filename).unwrap();
+    let last = bytes.len() - 12;
+    bytes[last] ^= 0xff;
+    std::fs::write(filename, &bytes).unwrap();
+    let err = LSH::load(filename)
+      .err()
+      .expect("corrupt index should not load");
+    assert!(err.to_string().contains("checksum mismatch in index data"));
+
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+
+    // A header whose reservoir size would overflow the row size is rejected.
+    let mut e = Encoder::new(Vec::new());
+    e.write_header(LSH_MAGIC, LSH_VERSION).unwrap();
+    for &x in &[1, 0, u64::MAX, 0] {
+      e.write_u64(x).unwrap();
+    }
+    e.write_u32(0).unwrap();
+    e.write_u32(0).unwrap();
+    e.write_checksum().unwrap();
+    let bytes = e.into_inner();
+    let err = LSH::read_from(&mut Decoder::new(&bytes[..]))
+      .err()
+      .expect("overflowing index should not load");
+    assert!(err.to_string().contains("invalid index dimensions"));
+  }
+
+  #[test]
+  fn test_unbounded_reservoir() {
+    // 64 single row tables that each receive ids 0..2000, far more than the reservoir holds.
+    let tables = 64;
+    let reservoir_size = 10;
+    let n = 2000;
+    let mut lsh = LSH::from_seed(tables, 0, reservoir_size, 5);
+    let hashes = vec![0; n * tables];
+    lsh.insert_range(0, n, &hashes);
+
+    let mut kept = vec![0; n];
+    let mut buckets: Vec<Vec<IDType>> = Vec::new();
+    for t in 0..tables {
+      let offset = t * lsh.table_size;
+      assert_eq!(dense(&lsh)[offset] as usize, n);
+      let mut bucket: Vec<IDType> = (1..reservoir_size + 1)
+        .map(|i| dense(&lsh)[offset + i])
+        .collect();
+      for &id in &bucket {
+        kept[id as usize] += 1;
+      }
+      bucket.sort();
+      bucket.dedup();
+      assert_eq!(bucket.len(), reservoir_size);
+      buckets.push(bucket);
+    }
+
+    // Each id is kept with probability 10 / 2000 per table, so early and late ids should be
+    // kept about equally often: 64 * 10 / 2 = 320 times per half.
+    let first: usize = kept[..n / 2].iter().sum();
+    let second: usize = kept[n / 2..].iter().sum();
+    assert_eq!(first + second, tables * reservoir_size);
+    assert!(first > 250 && second > 250);
+
+    // Buckets fed the same stream should make independent choices.
+    buckets.sort();
+    buckets.dedup();
+    assert_eq!(buckets.len(), tables);
+  }
+
+  #[test]
+  fn test_eviction_policies() {
+    let hashes = vec![0; 10];
+
+    let mut newest = LSH::new(1, 0, 4).with_eviction(EvictionPolicy::KeepNewest);
+    newest.insert_range(0, 10, &hashes);
+    let mut kept: Vec<IDType> = (**dense(&newest))[1..5].to_vec();
+    kept.sort();
+    assert_eq!(dense(&newest)[0], 10);
+    assert_eq!(kept, vec![6, 7, 8, 9]);
+
+    let mut oldest = LSH::new(1, 0, 4).with_eviction(EvictionPolicy::KeepOldest);
+    oldest.insert_range(0, 10, &hashes);
+    assert_eq!(dense(&oldest)[0], 10);
+    assert_eq!((**dense(&oldest))[1..5], [0, 1, 2, 3]);
+
+    let filename = "./temp_lsh_eviction_test_file";
+    newest.save(filename).expect("index should save");
+    let mut loaded = LSH::load(filename).expect("index should load");
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+    assert_eq!(loaded.eviction(), EvictionPolicy::KeepNewest);
+
+    loaded.insert_range(10, 2, &hashes);
+    let mut kept: Vec<IDType> = (**dense(&loaded))[1..5].to_vec();
+    kept.sort();
+    assert_eq!(kept, vec![8, 9, 10, 11]);
+
+    assert_eq!(
+      "newest".parse::<EvictionPolicy>(),
+      Ok(EvictionPolicy::KeepNewest)
+    );
+    assert!("fifo".parse::<EvictionPolicy>().is_err());
+  }
+
+  #[test]
+  fn test_keep_oldest_overflow() {
+    let mut lsh = LSH::from_seed(4, 2, 4, 1).with_eviction(EvictionPolicy::KeepOldest);
+    lsh.insert(&FIRST_IDS, &FIRST_HASHES);
+    do_second_insert(&mut lsh);
+
+    let xx = IDType::MAX;
+
+    // The overflowing rows, row 0 of table 2 and row 3 of table 3, keep their first four items
+    // whatever the seed.
+    let expected = [
+      2, 1, 6, xx, xx, 0, xx, xx, xx, xx, 4, 2, 4, 5, 7, 1, 3, xx, xx, xx, 2, 1, 3, xx, xx, 2, 2,
+      5, xx, xx, 1, 6, xx, xx, xx, 2, 4, 7, xx, xx, 6, 2, 3, 4, 5, 1, 1, xx, xx, xx, 0, xx, xx, xx,
+      xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 5, xx, xx, xx, 1, 2, xx, xx, xx, 5, 1, 3, 4, 6,
+    ];
+    for i in 0..80 {
+      assert_eq!(dense(&lsh)[i], expected[i]);
+    }
+
+    let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
+    let result = lsh.query(&hashes, 4);
+    assert_eq!(result.ids(0).collect::<Vec<_>>(), vec![6, 1, 3, 4]);
+    assert_eq!(result.ids(1).collect::<Vec<_>>(), vec![5, 2]);
+    assert_eq!(result.ids(2).collect::<Vec<_>>(), vec![6]);
+  }
+
+  #[test]
+  fn test_remove() {
+    let mut lsh = do_simple_insert();
+    let xx = IDType::MAX;
+
+    // Item 2 was inserted with hashes [2, 1, 0, 2].
+    assert_eq!(lsh.remove(2, &[2, 1, 0, 2]), 4);
+    assert_eq!(lsh.remove(2, &[2, 1, 0, 2]), 0);
+
+    let expected = [
+      1, 1, xx, xx, xx, 0, xx, xx, xx, xx, 1, 4, xx, xx, xx, 1, 3, xx, xx, xx, 2, 1, 3, xx, xx, 0,
+      xx, xx, xx, xx, 0, xx, xx, xx, xx, 1, 4, xx, xx, xx, 2, 3, 4, xx, xx, 1, 1, xx, xx, xx, 0,
+      xx, xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx, 0, xx, xx, xx, xx,
+      3, 1, 3, 4, xx,
+    ];
+    assert_eq!(**dense(&lsh), expected[..]);
+
+    assert_eq!(lsh.remove_by_scan(4), 4);
+    for i in 0..dense(&lsh).len() {
+      if i % lsh.row_size != 0 {
+        assert_ne!(dense(&lsh)[i], 4);
+      }
+    }
+
+    let hashes = [0, 2, 3, 3, 1, 1, 2, 1, 1, 2, 2, 0];
+    let result = lsh.query(&hashes, 4);
+    for q in 0..3 {
+      assert!(result.ids(q).all(|id| id != 2 && id != 4));
+    }
+  }
+
+  #[test]
+  fn test_remove_overflowed() {
+    let hashes = vec![0; 10];
+
+    let mut lsh = LSH::new(1, 0, 4).with_eviction(EvictionPolicy::KeepNewest);
+    lsh.insert_range(0, 10, &hashes);
+    assert_eq!(lsh.remove(8, &[0]), 1);
+    assert_eq!(dense(&lsh)[0], 3);
+    assert_eq!((**dense(&lsh))[1..5], [6, 7, 9, IDType::MAX]);
+
+    // The freed slot is filled first, then the oldest remaining item is evicted.
+    lsh.insert_range(10, 2, &hashes);
+    assert_eq!((**dense(&lsh))[1..5], [11, 7, 9, 10]);
+
+    let mut lsh = LSH::from_seed(1, 0, 4, 1);
+    lsh.insert_range(0, 100, &hashes.repeat(10));
+    let victim = dense(&lsh)[2];
+    assert_eq!(lsh.remove_by_scan(victim), 1);
+    assert_eq!(dense(&lsh)[0], 3);
+    lsh.insert(&[500], &[0]);
+    assert_eq!(dense(&lsh)[0], 4);
+    assert_eq!(dense(&lsh)[4], 500);
+  }
+
+  #[test]
+  fn test_update() {
+    let mut lsh = do_simple_insert();
+
+    lsh.update(3, &[3, 0, 0, 3], &[1, 1, 1, 1]);
+
+    for t in 0..4 {
+      let offset = t * lsh.table_size + lsh.row_size;
+      let count = dense(&lsh)[offset] as usize;
+      assert_eq!(dense(&lsh)[offset + count], 3);
+    }
+    assert_eq!(lsh.remove_by_scan(3), 4);
+  }
+
+  /// Checks that every row of `a` holds the same count and items as the same row of `b`.
+  fn assert_same_rows(a: &LSH, b: &LSH) {
+    for t in 0..a.tables {
+      for r in 0..a.rows {
+        let (x, y) = (a.row(t, r), b.row(t, r));
+        let stored = std::cmp::min(x[0] as usize, a.reservoir_size);
+        assert_eq!(x[..stored + 1], y[..stored + 1], "table {} row {}", t, r);
+      }
+    }
+  }
+
+  #[test]
+  fn test_sparse_storage() {
+    let (tables, range_pow, reservoir_size, n) = (8, 6, 4, 600);
+    let mut rng = StdRng::seed_from_u64(21);
+    let hashes: Vec<HashType> = (0..n * tables).map(|_| rng.gen_range(0..64)).collect();
+    let queries = &hashes[..50 * tables];
+
+    let build = |storage, eviction| {
+      let rng = &mut StdRng::seed_from_u64(8);
+      LSH::with_storage(tables, range_pow, reservoir_size, storage, rng)
+        .unwrap()
+        .with_eviction(eviction)
+    };
+
+    for &eviction in &[
+      EvictionPolicy::Reservoir,
+      EvictionPolicy::KeepNewest,
+      EvictionPolicy::KeepOldest,
+    ] {
+      let mut dense = build(Storage::Dense, eviction);
+      let mut sparse = build(Storage::Sparse, eviction);
+      assert_eq!(sparse.storage(), Storage::Sparse);
+      dense.insert_range(0, n, &hashes);
+      sparse.insert_range(0, n, &hashes);
+      assert_same_rows(&dense, &sparse);
+
+      let mut parallel = build(Storage::Sparse, eviction);
+      parallel.insert_range_parallel(0, n, &hashes, 3);
+      assert_same_rows(&dense, &parallel);
+
+      for id in (0..n as IDType).step_by(7) {
+        let own = &hashes[id as usize * tables..(id as usize + 1) * tables];
+        assert_eq!(dense.remove(id, own), sparse.remove(id, own));
+      }
+      for id in (3..n as IDType).step_by(11) {
+        assert_eq!(dense.remove_by_scan(id), sparse.remove_by_scan(id));
+      }
+      dense.insert_range(n as IDType, 100, &hashes);
+      sparse.insert_range(n as IDType, 100, &hashes);
+      assert_same_rows(&dense, &sparse);
+
+      let expected = dense.query_parallel(queries, 10, 2);
+      let result = sparse.query_parallel(queries, 10, 2);
+      for q in 0..50 {
+        assert!(result.nth(q).eq(expected.nth(q)));
+      }
+
+      let mut e = Encoder::new(Vec::new());
+      sparse.write_to(&mut e).unwrap();
+      let bytes = e.into_inner();
+      let loaded = LSH::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+      assert_eq!(loaded.storage(), Storage::Sparse);
+      assert_eq!(loaded.eviction(), eviction);
+      assert_same_rows(&dense, &loaded);
+    }
+
+    // Emptied buckets are dropped rather than left allocated.
+    let mut sparse = build(Storage::Sparse, EvictionPolicy::Reservoir);
+    sparse.insert(&[1, 2], &[5; 16]);
+    sparse.remove(1, &[5; 8]);
+    sparse.remove_by_scan(2);
+    match &sparse.buckets {
+      Buckets::Sparse(maps) => assert!(maps.iter().all(|map| map.is_empty())),
+      _ => panic!("expected sparse storage"),
+    }
+
+    let mut concurrent = build(Storage::Sparse, EvictionPolicy::Reservoir);
+    {
+      let inserter = concurrent.concurrent_inserter();
+      std::thread::scope(|scope| {
+        for i in 0..3 {
+          let inserter = &inserter;
+          let hashes = &hashes[i * 200 * tables..(i + 1) * 200 * tables];
+          scope.spawn(move || inserter.insert_range((i * 200) as IDType, 200, hashes));
+        }
+      });
+    }
+    let mut sequential = build(Storage::Dense, EvictionPolicy::KeepOldest);
+    sequential.insert_range(0, n, &hashes);
+    for t in 0..tables {
+      for r in 0..64 {
+        assert_eq!(concurrent.row(t, r)[0], sequential.row(t, r)[0]);
+      }
+    }
+  }
+
+  #[test]
+  fn test_varint() {
+    let values = [0, 1, 127, 128, 300, 16383, 16384, u32::MAX - 1, u32::MAX];
+    let mut bytes = Vec::new();
+    for &x in &values {
+      write_varint(&mut bytes, x);
+    }
+    assert_eq!(bytes.len(), 1 + 1 + 1 + 2 + 2 + 2 + 3 + 5 + 5);
+    let mut pos = 0;
+    for &x in &values {
+      assert_eq!(read_varint(&bytes, &mut pos), Some(x));
+    }
+    assert_eq!(pos, bytes.len());
+
+    // Truncated and overlong varints.
+    assert_eq!(read_varint(&[0x80], &mut 0), None);
+    assert_eq!(
+      read_varint(&[0xff, 0xff, 0xff, 0xff, 0x0f], &mut 0),
+      Some(u32::MAX)
+    );
+    assert_eq!(read_varint(&[0xff, 0xff, 0xff, 0xff, 0x1f], &mut 0), None);
+    assert_eq!(
+      read_varint(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], &mut 0),
+      None
+    );
+  }
+
+  #[test]
+  fn test_freeze() {
+    let (tables, n) = (8, 600);
+    assert!(LSH::with_storage(tables, 6, 4, Storage::Frozen, &mut thread_rng()).is_err());
+    let mut rng = StdRng::seed_from_u64(5);
+    let hashes: Vec<HashType> = (0..n * tables).map(|_| rng.gen_range(0..64)).collect();
+
+    for &storage in &[Storage::Dense, Storage::Sparse] {
+      let mut lsh =
+        LSH::with_storage(tables, 6, 4, storage, &mut StdRng::seed_from_u64(3)).unwrap();
+      lsh.insert_range(1_000_000, n, &hashes);
+      lsh.remove_by_scan(1_000_010);
+
+      let mut frozen =
+        LSH::with_storage(tables, 6, 4, storage, &mut StdRng::seed_from_u64(3)).unwrap();
+      frozen.insert_range(1_000_000, n, &hashes);
+      frozen.remove_by_scan(1_000_010);
+      assert!(!frozen.is_frozen());
+      frozen.freeze();
+      assert_eq!(frozen.storage(), Storage::Frozen);
+      assert!(frozen.is_frozen());
+
+      let (mut a, mut b) = (Vec::new(), Vec::new());
+      for t in 0..tables {
+        for r in 0..64 {
+          a.clear();
+          b.clear();
+          assert_eq!(
+            lsh.read_bucket(t, r, &mut a),
+            frozen.read_bucket(t, r, &mut b)
+          );
+          a.sort_unstable();
+          assert_eq!(a, b);
+        }
+      }
+
+      let expected = lsh.query_parallel(&hashes, 5, 2);
+      let result = frozen.query_parallel(&hashes, 5, 3);
+      for q in 0..n {
+        assert!(result.nth(q).eq(expected.nth(q)));
+      }
+      assert_eq!(format!("{}", frozen).len(), format!("{}", lsh).len());
+
+      let mut e = Encoder::new(Vec::new());
+      frozen.write_to(&mut e).unwrap();
+      let bytes = e.into_inner();
+      let loaded = LSH::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+      assert_eq!(loaded.storage(), Storage::Frozen);
+      let result = loaded.query(&hashes, 5);
+      for q in 0..n {
+        assert!(result.nth(q).eq(expected.nth(q)));
+      }
+
+      let mut corrupt = bytes.clone();
+      let last = corrupt.len() - 9;
+      corrupt[last] ^= 1;
+      assert!(LSH::read_from(&mut Decoder::new(&corrupt[..])).is_err());
+
+      // A bucket cut off in the middle of a varint is rejected even with a valid checksum, and so
+      // are items that overflow an ID.
+      let mut truncated = loaded;
+      if let Buckets::Frozen(frozen) = &mut truncated.buckets {
+        *frozen.bytes.last_mut().unwrap() |= 0x80;
+      }
+      let mut e = Encoder::new(Vec::new());
+      truncated.write_to(&mut e).unwrap();
+      let bytes = e.into_inner();
+      let err = LSH::read_from(&mut Decoder::new(&bytes[..])).err().unwrap();
+      assert!(err.to_string().contains("corrupt"));
+    }
+
+    let mut overflow =
+      LSH::with_storage(1, 0, 2, Storage::Sparse, &mut StdRng::seed_from_u64(3)).unwrap();
+    overflow.insert(&[u32::MAX - 1], &[0]);
+    overflow.freeze();
+    if let Buckets::Frozen(frozen) = &mut overflow.buckets {
+      // Count 2, then u32::MAX - 1 and a difference of 2 to the next item.
+      frozen.bytes.push(2);
+      frozen.bytes[0] = 2;
+      frozen.ends[0] += 1;
+      frozen.bases[1] += 1;
+    }
+    let mut e = Encoder::new(Vec::new());
+    overflow.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let err = LSH::read_from(&mut Decoder::new(&bytes[..])).err().unwrap();
+    assert!(err.to_string().contains("corrupt"));
+  }
+
+  #[test]
+  fn test_concurrent_insert() {
+    let tables = 4;
+    let n = 40000;
+    let threads = 8;
+    let mut rng = StdRng::seed_from_u64(11);
+    let hashes: Vec<HashType> = (0..n * tables).map(|_| rng.gen_range(0..64)).collect();
+
+    let mut sequential = LSH::new(tables, 6, 8);
+    sequential.insert_range(0, n, &hashes);
+
+    let mut concurrent = LSH::new(tables, 6, 8);
+    {
+      let inserter = concurrent.concurrent_inserter();
+      let shard = n / threads;
+      std::thread::scope(|scope| {
+        for i in 0..threads {
+          let inserter = &inserter;
+          let hashes = &hashes[i * shard * tables..(i + 1) * shard * tables];
+          scope.spawn(move || {
+            // Alternate between the two entry points to exercise both.
+            if i % 2 == 0 {
+              inserter.insert_range((i * shard) as IDType, shard, hashes);
+            } else {
+              let ids: Vec<IDType> = (i * shard..(i + 1) * shard).map(|x| x as IDType).collect();
+              inserter.insert(&ids, hashes);
+            }
+          });
+        }
+      });
+    }
+
+    for t in 0..tables {
+      for r in 0..64 {
+        let offset = t * sequential.table_size + r * sequential.row_size;
+        let count = dense(&sequential)[offset];
+        assert_eq!(dense(&concurrent)[offset], count);
+
+        let slots = std::cmp::min(count as usize, 8);
+        let mut items: Vec<IDType> = (1..slots + 1)
+          .map(|i| dense(&concurrent)[offset + i])
+          .collect();
+        items.sort();
+        items.dedup();
+        assert_eq!(items.len(), slots);
+        for &id in &items {
+          assert_eq!(hashes[id as usize * tables + t] as usize, r);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_insert_range_parallel() {
+    let tables = 6;
+    let n = 5000;
+    let mut rng = StdRng::seed_from_u64(4);
+    let hashes: Vec<HashType> = (0..n * tables).map(|_| rng.gen_range(0..32)).collect();
+
+    let mut serial = LSH::from_seed(tables, 5, 16, 21);
+    serial.insert_range(7, n, &hashes);
+
+    for &threads in &[1, 4, 6, 16] {
+      let mut parallel = LSH::from_seed(tables, 5, 16, 21);
+      parallel.insert_range_parallel(7, n, &hashes, threads);
+      assert_eq!(**dense(&parallel), **dense(&serial));
+    }
+  }
+
+  #[test]
+  fn test_query_parallel() {
+    let tables = 8;
+    let n = 2000;
+    let num_query = 101;
+    let mut rng = StdRng::seed_from_u64(9);
+    let hashes: Vec<HashType> = (0..n * tables).map(|_| rng.gen_range(0..16)).collect();
+    let query_hashes: Vec<HashType> = (0..num_query * tables)
+      .map(|_| rng.gen_range(0..16))
+      .collect();
+
+    let mut lsh = LSH::from_seed(tables, 4, 4, 2);
+    lsh.insert_range(0, n, &hashes);
+
+    let k = 10;
+    let serial = lsh.query(&query_hashes, k);
+    for &threads in &[1, 3, 8, 200] {
+      let parallel = lsh.query_parallel(&query_hashes, k, threads);
+      assert_eq!(parallel.len(), num_query);
+      for q in 0..num_query {
+        assert!(parallel.nth(q).eq(serial.nth(q)));
+      }
+
+      let above = lsh.query_above(&query_hashes, 2, threads);
+      let expected = lsh.query_above(&query_hashes, 2, 1);
+      assert_eq!(above.len(), num_query);
+      for q in 0..num_query {
+        assert!(above.nth(q).eq(expected.nth(q)));
+        assert!(above.nth(q).all(|(_, score)| score >= 2));
+      }
+    }
+  }
+
+  /// The original aggregation, counting candidates in a HashMap and fully sorting them. Used as
+  /// a reference for `query`, which aggregates in `query_batch`, and as the baseline in
+  /// `bench_query`.
+  fn hashmap_query(lsh: &LSH, hashes: &[HashType], k: usize) -> Vec<Vec<(IDType, u32)>> {
+    let mut results = Vec::new();
+    let mut counts: std::collections::HashMap<IDType, u32> = std::collections::HashMap::new();
+    for q in hashes.chunks(lsh.tables) {
+      for t in 0..lsh.tables {
+        let offset = t * lsh.table_size + q[t] as usize * lsh.row_size;
+        let count = std::cmp::min(dense(lsh)[offset] as usize, lsh.reservoir_size);
+        for i in 1..count + 1 {
+          *counts.entry(dense(lsh)[offset + i]).or_insert(0) += 1;
+        }
+      }
+      let mut topk: Vec<_> = counts.drain().collect();
+      topk.sort_by_key(|&(_, cnt)| std::cmp::Reverse(cnt));
+      topk.truncate(k);
+      results.push(topk);
+    }
+    return results;
+  }
+
+  /// Hashes for `n` items drawn around `clusters` centres, where each hash is replaced by a random
+  /// one with probability `noise`, so that items in a cluster collide in most tables.
+  fn clustered_hashes(
+    rng: &mut StdRng,
+    n: usize,
+    tables: usize,
+    range: HashType,
+    clusters: usize,
+    noise: f64,
+  ) -> Vec<HashType> {
+    let centres: Vec<HashType> = (0..clusters * tables)
+      .map(|_| rng.gen_range(0..range))
+      .collect();
+    let mut hashes = Vec::with_capacity(n * tables);
+    for _ in 0..n {
+      let c = rng.gen_range(0..clusters);
+      for t in 0..tables {
+        if rng.gen_bool(noise) {
+          hashes.push(rng.gen_range(0..range));
+        } else {
+          hashes.push(centres[c * tables + t]);
+        }
+      }
+    }
+    return hashes;
+  }
+
+  #[test]
+  fn test_query_multiprobe() {
+    let tables = 8;
+    let probes = 3;
+    let mut rng = StdRng::seed_from_u64(12);
+    let hashes = clustered_hashes(&mut rng, 3000, tables, 1 << 6, 40, 0.3);
+    let mut lsh = LSH::from_seed(tables, 6, 8, 5);
+    lsh.insert_range(0, 3000, &hashes);
+
+    let query_hashes = clustered_hashes(&mut rng, 30, tables, 1 << 6, 40, 0.3);
+    let no_probes = lsh.query_multiprobe(&query_hashes, 0, 10, 1);
+    let plain = lsh.query(&query_hashes, 10);
+    for q in 0..30 {
+      assert!(no_probes.nth(q).eq(plain.nth(q)));
+    }
+
+    // Include some repeated buckets, which must not count an item twice.
+    let probe_hashes: Vec<HashType> = (0..30 * tables * (probes + 1))
+      .map(|i| {
+        if i % 7 == 0 {
+          query_hashes[i / (probes + 1)]
+        } else {
+          rng.gen_range(0..1 << 6)
+        }
+      })
+      .collect();
+    let k = 10;
+    let result = lsh.query_multiprobe(&probe_hashes, probes, k, 4);
+    for (q, query) in probe_hashes.chunks(tables * (probes + 1)).enumerate() {
+      let mut counts: std::collections::HashMap<IDType, u32> = std::collections::HashMap::new();
+      for (t, buckets) in query.chunks(probes + 1).enumerate() {
+        let mut found = std::collections::HashSet::new();
+        for &b in buckets {
+          let offset = t * lsh.table_size + b as usize * lsh.row_size;
+          let count = std::cmp::min(dense(&lsh)[offset] as usize, lsh.reservoir_size);
+          found.extend(
+            (**dense(&lsh))[offset + 1..offset + 1 + count]
+              .iter()
+              .copied(),
+          );
+        }
+        for id in found {
+          *counts.entry(id).or_insert(0) += 1;
+        }
+      }
+      let mut best: Vec<(IDType, u32)> = counts.into_iter().collect();
+      best.sort_by_key(rank);
+      best.truncate(k);
+      assert!(result.nth(q).eq(best.into_iter()));
+    }
+  }
+
+  #[test]
+  fn test_query_counts() {
+    let tables = 16;
+    let mut rng = StdRng::seed_from_u64(4);
+    let hashes = clustered_hashes(&mut rng, 5000, tables, 1 << 8, 50, 0.3);
+    let query_hashes = clustered_hashes(&mut rng, 50, tables, 1 << 8, 50, 0.3);
+
+    let mut lsh = LSH::from_seed(tables, 8, 16, 1);
+    lsh.insert_range(0, 5000, &hashes);
+
+    let k = 20;
+    let result = lsh.query(&query_hashes, k);
+    let expected = hashmap_query(&lsh, &query_hashes, usize::MAX);
+    for q in 0..50 {
+      // Ranked by decreasing count, with ties broken by increasing ID.
+      let mut best = expected[q].clone();
+      best.sort_by_key(rank);
+      best.truncate(k);
+      assert!(result.nth(q).eq(best.into_iter()));
+    }
+  }
+
+  /// Compares the HashMap aggregation with `query` at the default config sizes. Run with
+  /// `cargo test --release bench_query -- --ignored --nocapture`.
+  #[test]
+  #[ignore]
+  fn bench_query() {
+    let n = 200000;
+    let num_query = 2000;
+    let k = 10;
+    for &(tables, range_pow, reservoir_size) in &[(16, 12, 16), (32, 15, 32), (64, 15, 64)] {
+      let range = 1 << range_pow;
+      let mut rng = StdRng::seed_from_u64(7);
+      let hashes = clustered_hashes(&mut rng, n, tables, range, 2000, 0.3);
+      let query_hashes = clustered_hashes(&mut rng, num_query, tables, range, 2000, 0.3);
+
+      let mut lsh = LSH::from_seed(tables, range_pow, reservoir_size, 1);
+      lsh.insert_range(0, n, &hashes);
+
+      let start = std::time::Instant::now();
+      let baseline = hashmap_query(&lsh, &query_hashes, k);
+      let hashmap_time = start.elapsed();
+
+      let start = std::time::Instant::now();
+      let result = lsh.query(&query_hashes, k);
+      let sort_time = start.elapsed();
+
+      assert_eq!(baseline.len(), result.len());
+      println!(
+        "tables {:>2} reservoir {:>2}: hashmap {:>8.2?} sort-and-count {:>8.2?} speedup {:.2}x",
+        tables,
+        reservoir_size,
+        hashmap_time,
+        sort_time,
+        hashmap_time.as_secs_f64() / sort_time.as_secs_f64()
+      );
+    }
+  }
+
+  #[test]
+  #[ignore]
+  fn bench_freeze() {
+    let n = 200000;
+    let num_query = 2000;
+    for &(tables, range_pow, reservoir_size) in &[(16, 12, 16), (32, 15, 32), (64, 15, 64)] {
+      let range = 1 << range_pow;
+      let mut rng = StdRng::seed_from_u64(7);
+      let hashes = clustered_hashes(&mut rng, n, tables, range, 2000, 0.3);
+      let query_hashes = clustered_hashes(&mut rng, num_query, tables, range, 2000, 0.3);
+
+      let mut lsh = LSH::from_seed(tables, range_pow, reservoir_size, 1);
+      lsh.insert_range(0, n, &hashes);
+      let dense_bytes = lsh.memory_bytes();
+
+      let start = std::time::Instant::now();
+      let expected = lsh.query(&query_hashes, 10);
+      let dense_time = start.elapsed();
+
+      lsh.freeze();
+      let start = std::time::Instant::now();
+      let result = lsh.query(&query_hashes, 10);
+      let frozen_time = start.elapsed();
+
+      for q in 0..num_query {
+        assert!(result.nth(q).eq(expected.nth(q)));
+      }
+      println!(
+        "tables {:>2} reservoir {:>2}: dense {:>6.1}MB {:>8.2?} frozen {:>5.1}MB {:>8.2?} \
+         ratio {:.1}x overhead {:.2}x",
+        tables,
+        reservoir_size,
+        dense_bytes as f64 / 1e6,
+        dense_time,
+        lsh.memory_bytes() as f64 / 1e6,
+        frozen_time,
+        dense_bytes as f64 / lsh.memory_bytes() as f64,
+        frozen_time.as_secs_f64() / dense_time.as_secs_f64()
+      );
+    }
+  }
+
+  #[test]
+  fn test_from_seed() {
+    let mut a = LSH::from_seed(4, 3, 2, 99);
+    let mut b = LSH::from_seed(4, 3, 2, 99);
+    assert_eq!(a.seed, b.seed);
+
+    let hashes: Vec<HashType> = (0..400).map(|x| (x * 7 + x / 3) % 8).collect();
+    a.insert_range(0, 100, &hashes);
+    b.insert_range(0, 100, &hashes);
+    assert_eq!(**dense(&a), **dense(&b));
+  }
+
   #[test]
   fn test_insert_range() {
     let ids = [1, 2, 3, 4];
@@ -340,8 +2626,8 @@ mod tests {
 
     lsh2.insert_range(1, 4, &hashes);
 
-    for i in 0..lsh1.data.len() {
-      assert_eq!(lsh1.data[i], lsh2.data[i]);
+    for i in 0..dense(&lsh1).len() {
+      assert_eq!(dense(&lsh1)[i], dense(&lsh2)[i]);
     }
   }
 }
@@ -1,140 +1,113 @@
+// The modules keep their original style of explicit returns and `field: field` initializers, so
+// each allows the clippy lints that style trips along with any it needs of its own.
+#[allow(clippy::needless_return)]
+mod cli;
+#[allow(clippy::needless_return)]
+mod commands;
+#[allow(clippy::needless_return)]
 mod config;
+#[allow(dead_code)]
+#[allow(
+  clippy::needless_return,
+  clippy::redundant_field_names,
+  clippy::upper_case_acronyms
+)]
 mod doph;
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
 mod evaluate;
 #[allow(dead_code)]
+#[allow(
+  clippy::needless_return,
+  clippy::redundant_field_names,
+  clippy::ptr_offset_with_cast
+)]
 mod heap_array;
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod index;
+#[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod keys;
+#[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod labels;
+#[allow(dead_code)]
+#[allow(
+  clippy::needless_return,
+  clippy::redundant_field_names,
+  clippy::upper_case_acronyms,
+  clippy::needless_range_loop,
+  clippy::write_with_newline,
+  mismatched_lifetime_syntaxes
+)]
 mod lsh;
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod persist;
+#[allow(clippy::needless_return)]
+mod plan;
+#[allow(dead_code)]
+#[allow(
+  clippy::needless_return,
+  clippy::redundant_field_names,
+  clippy::needless_borrow,
+  clippy::explicit_counter_loop,
+  clippy::useless_vec,
+  clippy::unused_io_amount,
+  mismatched_lifetime_syntaxes
+)]
 mod reader;
 #[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod stats;
+#[allow(dead_code)]
+#[allow(clippy::needless_return)]
+mod store;
+#[allow(dead_code)]
+#[allow(clippy::redundant_closure)]
 mod thread_pool;
 
-use config::{Config, DataConfig, LSHConfig};
-use doph::DOPH;
-use evaluate::average_cosine_similarity;
-use lsh::{HashType, IDType, LSH};
-use reader::read_data_svm;
+use cli::{parse_args, Command, USAGE};
 
 use std::env;
-
-const TEST_CONFIG: Config = Config {
-  lsh: LSHConfig {
-    tables: 0,
-    k: 0,
-    range_pow: 0,
-    reservoir_size: 0,
-  },
-
-  data: DataConfig {
-    filename: "",
-    avg_dim: 0,
-    num_data: 0,
-    num_query: 0,
-  },
-
-  topk: 0,
-  simk: 0,
-};
+use std::process;
 
 fn main() {
-  let args: Vec<String> = env::args().collect();
+  let args: Vec<String> = env::args().skip(1).collect();
 
-  if args.len() != 2 {
-    eprintln!("Usage: ./flash <config name>");
-    return;
-  }
-
-  let config = match args[1].as_str() {
-    "test" => &TEST_CONFIG,
-    x => {
-      panic!("Invalid mode \"{}\" entered", x);
+  let args = match parse_args(&args) {
+    Ok(a) => a,
+    Err(e) => {
+      eprintln!("Error: {}\n\n{}", e, USAGE);
+      process::exit(1);
     }
   };
 
-  println!(
-    "Reading {} vectors as insertion dataset",
-    config.data.num_data
-  );
-
-  let data = read_data_svm(
-    config.data.filename,
-    config.data.num_data,
-    config.data.avg_dim,
-    config.data.num_query,
-  );
-
-  println!("\t-Done");
-
-  let doph = DOPH::new(
-    config.lsh.tables,
-    config.lsh.k,
-    config.lsh.range_pow as HashType,
-  );
-
-  let mut lsh = LSH::new(
-    config.lsh.tables,
-    config.lsh.range_pow,
-    config.lsh.reservoir_size,
-  );
-
-  println!("Hashing data");
-
-  let hashes = doph.hash(data);
-
-  println!("\t-Done");
-
-  println!("Inserting data");
+  let config = &args.config;
 
-  lsh.insert_range(
-    config.data.num_query as IDType,
-    config.data.num_data,
-    &hashes,
-  );
-
-  println!("\t-Done");
-
-  println!("Reading {} vectors as query dataset", config.data.num_query);
-
-  let query = read_data_svm(
-    config.data.filename,
-    config.data.num_query,
-    config.data.avg_dim,
-    0,
-  );
-
-  println!("\t-Done");
-
-  println!("Hashing queries");
-
-  let query_hashes = doph.hash(query);
-
-  println!("\t-Done");
-
-  println!("Querying data");
-
-  let results = lsh.query(&query_hashes, config.topk);
-
-  println!("\t-Done");
-
-  println!(
-    "Reading all {} vectors for evaluation",
-    config.data.num_data + config.data.num_query
-  );
-
-  let all_data = read_data_svm(
-    config.data.filename,
-    config.data.num_data + config.data.num_query,
-    config.data.avg_dim,
-    0,
-  );
-
-  println!("\t-Done");
-
-  println!("Computing average cosine similarity");
+  // Stats only reads the index, so it does not need a data set configured.
+  if args.command != Command::Stats {
+    if let Err(e) = config.validate() {
+      eprintln!("Error: {}", e);
+      process::exit(1);
+    }
+  }
 
-  let sim = average_cosine_similarity(0, config.data.num_query, results, &all_data, config.simk);
+  let status = match args.command {
+    Command::Build => commands::build(config, &args.index),
+    Command::Query => commands::query(config, &args.index, &args.output),
+    Command::Eval => commands::eval(config, &args.index, &args.results),
+    Command::Stats => commands::stats(&args.index),
+    Command::Plan => commands::plan(config),
+    Command::Sweep => {
+      let (key, values) = args.sweep.as_ref().expect("sweep should have values");
+      commands::sweep(config, key, values)
+    }
+  };
 
-  println!("Average cosine similarity @{} is {}", config.simk, sim);
+  if let Err(e) = status {
+    eprintln!("Error: {}", e);
+    process::exit(1);
+  }
 }
@@ -0,0 +1,298 @@
+use crate::heap_array::HeapAllocatedArray;
+
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+const CHUNK_LEN: usize = 1 << 14;
+
+pub fn invalid_data(msg: String) -> io::Error {
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn fnv1a(mut hash: u64, bytes: &[u8]) -> u64 {
+  for &b in bytes {
+    hash ^= b as u64;
+    hash = hash.wrapping_mul(FNV_PRIME);
+  }
+  return hash;
+}
+
+/// Writes little endian values while keeping a running FNV-1a checksum of every byte written
+/// since the last call to `write_checksum`.
+pub struct Encoder<W: Write> {
+  inner: W,
+  checksum: u64,
+}
+
+impl<W: Write> Encoder<W> {
+  pub fn new(inner: W) -> Self {
+    Encoder {
+      inner,
+      checksum: FNV_OFFSET,
+    }
+  }
+
+  pub fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+    self.checksum = fnv1a(self.checksum, bytes);
+    self.inner.write_all(bytes)
+  }
+
+  pub fn write_u32(&mut self, x: u32) -> io::Result<()> {
+    self.write_bytes(&x.to_le_bytes())
+  }
+
+  pub fn write_u64(&mut self, x: u64) -> io::Result<()> {
+    self.write_bytes(&x.to_le_bytes())
+  }
+
+  /// Writes a magic string followed by the format version.
+  pub fn write_header(&mut self, magic: &[u8; 8], version: u32) -> io::Result<()> {
+    self.write_bytes(magic)?;
+    self.write_u32(version)
+  }
+
+  pub fn write_u32_slice(&mut self, xs: &[u32]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(CHUNK_LEN * 4);
+    for chunk in xs.chunks(CHUNK_LEN) {
+      buf.clear();
+      for x in chunk {
+        buf.extend_from_slice(&x.to_le_bytes());
+      }
+      self.write_bytes(&buf)?;
+    }
+    Ok(())
+  }
+
+  pub fn write_u64_slice(&mut self, xs: &[u64]) -> io::Result<()> {
+    for &x in xs {
+      self.write_u64(x)?;
+    }
+    Ok(())
+  }
+
+  /// Writes the checksum of everything written since the previous checksum and starts a new one.
+  pub fn write_checksum(&mut self) -> io::Result<()> {
+    let c = self.checksum;
+    self.inner.write_all(&c.to_le_bytes())?;
+    self.checksum = FNV_OFFSET;
+    Ok(())
+  }
+
+  pub fn into_inner(self) -> W {
+    self.inner
+  }
+}
+
+/// Reads values written by an `Encoder`, verifying checksums as it goes.
+pub struct Decoder<R: Read> {
+  inner: R,
+  checksum: u64,
+}
+
+impl<R: Read> Decoder<R> {
+  pub fn new(inner: R) -> Self {
+    Decoder {
+      inner,
+      checksum: FNV_OFFSET,
+    }
+  }
+
+  pub fn read_bytes(&mut self, buf: &mut [u8]) -> io::Result<()> {
+    self.inner.read_exact(buf)?;
+    self.checksum = fnv1a(self.checksum, buf);
+    Ok(())
+  }
+
+  pub fn read_u32(&mut self) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    self.read_bytes(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+  }
+
+  pub fn read_u64(&mut self) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    self.read_bytes(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+  }
+
+  /// Reads a length or dimension, failing if it does not fit in a usize on this machine.
+  pub fn read_usize(&mut self) -> io::Result<usize> {
+    let x = self.read_u64()?;
+    if x > usize::MAX as u64 {
+      return Err(invalid_data(format!("value {} is too large", x)));
+    }
+    Ok(x as usize)
+  }
+
+  /// Checks the magic string and returns the format version, which must be at most `max_version`.
+  pub fn read_header(&mut self, magic: &[u8; 8], max_version: u32) -> io::Result<u32> {
+    let mut found = [0; 8];
+    self.read_bytes(&mut found)?;
+    if &found != magic {
+      return Err(invalid_data(format!(
+        "expected magic {:?} but found {:?}",
+        String::from_utf8_lossy(magic),
+        String::from_utf8_lossy(&found)
+      )));
+    }
+
+    let version = self.read_u32()?;
+    if version == 0 || version > max_version {
+      return Err(invalid_data(format!(
+        "unsupported format version {}, expected at most {}",
+        version, max_version
+      )));
+    }
+    Ok(version)
+  }
+
+  pub fn read_u32_array(&mut self, len: usize) -> io::Result<HeapAllocatedArray<u32>> {
+    let mut arr = HeapAllocatedArray::new(len);
+    let mut buf = vec![0; CHUNK_LEN * 4];
+    let mut i = 0;
+    while i < len {
+      let n = std::cmp::min(CHUNK_LEN, len - i);
+      self.read_bytes(&mut buf[..n * 4])?;
+      for j in 0..n {
+        let mut b = [0; 4];
+        b.copy_from_slice(&buf[j * 4..j * 4 + 4]);
+        arr[i + j] = u32::from_le_bytes(b);
+      }
+      i += n;
+    }
+    Ok(arr)
+  }
+
+  pub fn read_u64_array(&mut self, len: usize) -> io::Result<HeapAllocatedArray<u64>> {
+    let mut arr = HeapAllocatedArray::new(len);
+    for i in 0..len {
+      arr[i] = self.read_u64()?;
+    }
+    Ok(arr)
+  }
+
+  /// Reads a stored checksum and compares it with the checksum of everything read since the
+  /// previous one. `section` names the data covered in the error message.
+  pub fn verify_checksum(&mut self, section: &str) -> io::Result<()> {
+    let expected = self.checksum;
+    let mut buf = [0; 8];
+    self.inner.read_exact(&mut buf)?;
+    let found = u64::from_le_bytes(buf);
+    if found != expected {
+      return Err(invalid_data(format!(
+        "checksum mismatch in {}: stored {:016x}, computed {:016x}",
+        section, found, expected
+      )));
+    }
+    self.checksum = FNV_OFFSET;
+    Ok(())
+  }
+}
+
+/// Writes a file through a temporary file in the same directory which is renamed over `filename`
+/// once it is complete and synced, so a crash never leaves a partially written file behind.
+pub fn save_atomic<F>(filename: &str, f: F) -> io::Result<()>
+where
+  F: FnOnce(&mut Encoder<BufWriter<File>>) -> io::Result<()>,
+{
+  let tmp = format!("{}.tmp", filename);
+
+  let result = File::create(&tmp).and_then(|file| {
+    let mut encoder = Encoder::new(BufWriter::new(file));
+    f(&mut encoder)?;
+    let file = encoder
+      .into_inner()
+      .into_inner()
+      .map_err(|e| e.into_error())?;
+    file.sync_all()
+  });
+
+  match result {
+    Ok(()) => fs::rename(&tmp, filename),
+    Err(e) => {
+      let _ = fs::remove_file(&tmp);
+      Err(e)
+    }
+  }
+}
+
+pub fn load<T, F>(filename: &str, f: F) -> io::Result<T>
+where
+  F: FnOnce(&mut Decoder<BufReader<File>>) -> io::Result<T>,
+{
+  let mut decoder = Decoder::new(BufReader::new(File::open(filename)?));
+  let x = f(&mut decoder)?;
+
+  let mut trailing = [0; 1];
+  if decoder.inner.read(&mut trailing)? != 0 {
+    return Err(invalid_data(format!(
+      "unexpected data after the end of '{}'",
+      filename
+    )));
+  }
+  Ok(x)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_round_trip() {
+    let mut encoder = Encoder::new(Vec::new());
+    encoder.write_header(b"TESTFILE", 2).unwrap();
+    encoder.write_u64(12345678901).unwrap();
+    encoder.write_checksum().unwrap();
+    let xs: Vec<u32> = (0..40000).map(|x| x * 7).collect();
+    encoder.write_u32_slice(&xs).unwrap();
+    encoder.write_checksum().unwrap();
+    let bytes = encoder.into_inner();
+
+    let mut decoder = Decoder::new(&bytes[..]);
+    assert_eq!(decoder.read_header(b"TESTFILE", 2).unwrap(), 2);
+    assert_eq!(decoder.read_u64().unwrap(), 12345678901);
+    decoder.verify_checksum("header").unwrap();
+    let arr = decoder.read_u32_array(40000).unwrap();
+    decoder.verify_checksum("data").unwrap();
+    assert_eq!(*arr, xs[..]);
+
+    assert!(Decoder::new(&bytes[..])
+      .read_header(b"OTHERMAG", 2)
+      .is_err());
+    assert!(Decoder::new(&bytes[..])
+      .read_header(b"TESTFILE", 1)
+      .is_err());
+
+    let mut corrupt = bytes.clone();
+    corrupt[30000] ^= 1;
+    let mut decoder = Decoder::new(&corrupt[..]);
+    decoder.read_header(b"TESTFILE", 2).unwrap();
+    decoder.read_u64().unwrap();
+    decoder.verify_checksum("header").unwrap();
+    decoder.read_u32_array(40000).unwrap();
+    assert!(decoder.verify_checksum("data").is_err());
+  }
+
+  #[test]
+  fn test_save_atomic() {
+    let filename = "./temp_persist_test_file";
+
+    save_atomic(filename, |e| e.write_u32(17)).unwrap();
+    assert!(save_atomic(filename, |e| {
+      e.write_u32(18)?;
+      Err(io::Error::other("interrupted"))
+    })
+    .is_err());
+
+    assert!(!std::path::Path::new("./temp_persist_test_file.tmp").exists());
+    assert_eq!(load(filename, |d| d.read_u32()).unwrap(), 17);
+    assert!(load(filename, |_| Ok(())).is_err());
+
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+  }
+}
@@ -0,0 +1,332 @@
+use crate::config::{Config, LSHConfig};
+use crate::lsh::{HashType, IDType, Storage};
+use crate::stats::format_bytes;
+
+use std::fmt;
+use std::mem::size_of;
+
+/// The memory needed to build and query an index with a `Config`, computed from the sizes of
+/// the arrays that will be allocated rather than by allocating them, so that a config which does
+/// not fit can be rejected before any data is read. The parts add up to what `sweep` holds at
+/// once; `build` and `query` on their own need somewhat less.
+pub struct MemoryPlan {
+  /// The buckets of the index. Sparse and frozen storage only allocate the buckets items reach,
+  /// which is estimated by assuming items hash uniformly, and a frozen index also holds its
+  /// sparse buckets while it is being compressed.
+  pub index: usize,
+  /// The hash functions and the hashes of the data and the queries.
+  pub hashes: usize,
+  /// The query results and their scores.
+  pub results: usize,
+  /// The data and query vectors as read from the file, the copy of the data stored in the index
+  /// and the labels.
+  pub data: usize,
+}
+
+/// The bytes taken by `n` vectors with `avg_dim` non-zero elements each, as read by `reader`.
+fn vectors_bytes(n: usize, avg_dim: usize) -> usize {
+  let element = size_of::<HashType>() + size_of::<f32>();
+  return n
+    .saturating_add(1)
+    .saturating_mul(size_of::<usize>())
+    .saturating_add(n.saturating_mul(avg_dim).saturating_mul(element));
+}
+
+/// The number of bytes `write_varint` takes for `x`.
+fn varint_bytes(x: usize) -> usize {
+  let bits = (usize::BITS - x.leading_zeros()) as usize;
+  std::cmp::max(1, bits.div_ceil(7))
+}
+
+/// The bytes taken by the buckets of `lsh` once `num_data` items with IDs below `num_ids` are
+/// inserted. Sizes too large for a `usize` saturate at `usize::MAX`, so they never fit a budget.
+fn index_bytes(lsh: &LSHConfig, num_data: usize, num_ids: usize) -> usize {
+  let id = size_of::<IDType>();
+  let rows = 1usize << lsh.range_pow;
+  if lsh.storage == Storage::Dense {
+    return lsh
+      .tables
+      .saturating_mul(rows)
+      .saturating_mul(lsh.reservoir_size.saturating_add(1))
+      .saturating_mul(id);
+  }
+
+  // Each item reaches one bucket per table, so with uniform hashing the expected number of
+  // occupied buckets is rows * (1 - (1 - 1 / rows)^num_data).
+  let occupied = rows as f64 * -(-(num_data as f64) / rows as f64).exp_m1();
+  let occupied = std::cmp::max(occupied.ceil() as usize, 1);
+  let stored = std::cmp::min(num_data, occupied.saturating_mul(lsh.reservoir_size));
+
+  // Rows grow one slot at a time, so their capacity is rounded up to a power of two, and each is
+  // a separate allocation with a header of about two words. Maps keep their load below 7/8 with
+  // a control byte per slot.
+  let row_len = std::cmp::max((1 + stored / occupied).next_power_of_two(), 4);
+  let row = row_len * id + 2 * size_of::<usize>();
+  let slots = (occupied * 8 / 7 + 1).next_power_of_two();
+  let sparse = slots
+    .saturating_mul(size_of::<(HashType, Vec<IDType>)>() + 1)
+    .saturating_add(occupied.saturating_mul(row));
+  if lsh.storage == Storage::Sparse {
+    return lsh.tables.saturating_mul(sparse);
+  }
+
+  let frozen = rows
+    .saturating_mul(size_of::<u32>())
+    .saturating_add(occupied.saturating_mul(varint_bytes(num_data)))
+    .saturating_add(stored.saturating_mul(varint_bytes(num_ids)));
+  return lsh
+    .tables
+    .saturating_mul(sparse.saturating_add(frozen))
+    .saturating_add(
+      lsh
+        .tables
+        .saturating_add(1)
+        .saturating_mul(size_of::<u64>()),
+    );
+}
+
+impl MemoryPlan {
+  pub fn of(config: &Config) -> Self {
+    let lsh = &config.lsh;
+    let data = &config.data;
+    let num_ids = data.num_query.saturating_add(data.num_data);
+    let hash = size_of::<HashType>();
+
+    // Every size saturates rather than overflowing, as in `index_bytes`.
+    let hashes = lsh
+      .k
+      .saturating_mul(lsh.tables)
+      .saturating_add(data.num_data.saturating_mul(lsh.tables))
+      .saturating_add(
+        data
+          .num_query
+          .saturating_mul(lsh.tables)
+          .saturating_mul(lsh.probes.saturating_add(1)),
+      )
+      .saturating_mul(hash);
+
+    // Each result slot holds an ID and a count, and a copy of the counts as similarities while
+    // the scores are converted.
+    let k = match config.rerank {
+      Some(_) => config.candidates,
+      None => config.topk,
+    };
+    let mut results = data
+      .num_query
+      .saturating_mul(k.saturating_add(1))
+      .saturating_mul(size_of::<IDType>() + size_of::<u32>() + size_of::<f32>());
+    if config.rerank.is_some() {
+      results = results.saturating_add(
+        data
+          .num_query
+          .saturating_mul(config.topk.saturating_add(1))
+          .saturating_mul(size_of::<IDType>() + size_of::<f32>()),
+      );
+    }
+
+    // The store keeps its own copy of the data and a row number per ID, and a label is read for
+    // every vector and kept as a code.
+    let vectors = vectors_bytes(data.num_data, data.avg_dim);
+    let data_bytes = vectors
+      .saturating_mul(2)
+      .saturating_add(vectors_bytes(data.num_query, data.avg_dim))
+      .saturating_add(num_ids.saturating_mul(size_of::<u32>()))
+      .saturating_add(
+        data
+          .num_data
+          .saturating_mul(size_of::<String>() + size_of::<u32>()),
+      );
+
+    return MemoryPlan {
+      index: index_bytes(lsh, data.num_data, num_ids),
+      hashes,
+      results,
+      data: data_bytes,
+    };
+  }
+
+  pub fn total(&self) -> usize {
+    self
+      .index
+      .saturating_add(self.hashes)
+      .saturating_add(self.results)
+      .saturating_add(self.data)
+  }
+}
+
+impl fmt::Display for MemoryPlan {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    writeln!(f, "index    {:>10}", format_bytes(self.index))?;
+    writeln!(f, "hashes   {:>10}", format_bytes(self.hashes))?;
+    writeln!(f, "results  {:>10}", format_bytes(self.results))?;
+    writeln!(f, "data     {:>10}", format_bytes(self.data))?;
+    write!(f, "total    {:>10}", format_bytes(self.total()))
+  }
+}
+
+/// A change to a single parameter that brings a config within a memory budget.
+pub struct Suggestion {
+  pub key: &'static str,
+  pub value: usize,
+  pub plan: MemoryPlan,
+}
+
+/// Finds the largest value of `lsh.range_pow`, `lsh.reservoir_size` and `lsh.tables` below the
+/// current one for which `config` fits in `budget` bytes, changing one parameter at a time. A
+/// parameter is left out if no valid value fits, so the result is empty when the data alone
+/// exceeds the budget, or when `config` already fits.
+pub fn suggest(config: &Config, budget: usize) -> Vec<Suggestion> {
+  if MemoryPlan::of(config).total() <= budget {
+    return Vec::new();
+  }
+
+  let lsh = &config.lsh;
+  // Smaller ranges must still leave every min-hash bin at least one bucket.
+  let mut min_range_pow = 1;
+  while 1 << min_range_pow < lsh.k * lsh.tables {
+    min_range_pow += 1;
+  }
+
+  let params: [(&'static str, usize, usize); 3] = [
+    ("lsh.range_pow", min_range_pow, lsh.range_pow),
+    ("lsh.reservoir_size", 1, lsh.reservoir_size),
+    ("lsh.tables", 1, lsh.tables),
+  ];
+
+  let mut suggestions = Vec::new();
+  for &(key, min, current) in params.iter() {
+    let plan_with = |value: usize| {
+      let mut changed = config.clone();
+      changed
+        .set_from_str(key, &value.to_string())
+        .expect("planned parameters should be valid");
+      MemoryPlan::of(&changed)
+    };
+
+    // Memory only grows with each parameter, so search for the largest value that fits.
+    let (mut lo, mut hi) = (min, current);
+    if lo >= hi || plan_with(lo).total() > budget {
+      continue;
+    }
+    while hi - lo > 1 {
+      let mid = lo + (hi - lo) / 2;
+      if plan_with(mid).total() <= budget {
+        lo = mid;
+      } else {
+        hi = mid;
+      }
+    }
+    suggestions.push(Suggestion {
+      key,
+      value: lo,
+      plan: plan_with(lo),
+    });
+  }
+  return suggestions;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lsh::LSH;
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  fn config() -> Config {
+    let mut config = Config::default();
+    config.data.num_data = 10_000;
+    config.data.num_query = 100;
+    config.data.avg_dim = 50;
+    config.lsh.tables = 16;
+    config.lsh.range_pow = 12;
+    config.lsh.reservoir_size = 16;
+    return config;
+  }
+
+  #[test]
+  fn test_plan() {
+    let config = config();
+    let plan = MemoryPlan::of(&config);
+
+    // The dense index is exactly what `LSH` allocates.
+    let lsh = LSH::from_seed(16, 12, 16, 1);
+    assert_eq!(plan.index, lsh.memory_bytes());
+    assert_eq!(plan.hashes, (4 * 16 + 10_000 * 16 + 100 * 16) * 4);
+    assert_eq!(plan.results, 100 * 11 * 12);
+    assert_eq!(
+      plan.total(),
+      plan.index + plan.hashes + plan.results + plan.data
+    );
+
+    // Sparse storage only saves memory when most buckets are empty.
+    let mut sparse = config.clone();
+    sparse.lsh.storage = Storage::Sparse;
+    assert!(MemoryPlan::of(&sparse).index > plan.index);
+    sparse.lsh.range_pow = 16;
+    let mut dense = sparse.clone();
+    dense.lsh.storage = Storage::Dense;
+    let mut frozen = sparse.clone();
+    frozen.lsh.storage = Storage::Frozen;
+    assert!(MemoryPlan::of(&sparse).index < MemoryPlan::of(&dense).index);
+    assert!(MemoryPlan::of(&frozen).index > MemoryPlan::of(&sparse).index);
+
+    let mut rerank = config.clone();
+    rerank.rerank = Some(crate::evaluate::Similarity::Cosine);
+    assert!(MemoryPlan::of(&rerank).results > plan.results);
+
+    // Sizes past a usize saturate instead of overflowing.
+    let mut huge = config;
+    huge.lsh.tables = 1 << 31;
+    huge.lsh.range_pow = 31;
+    huge.lsh.reservoir_size = 1;
+    huge.lsh.probes = usize::MAX;
+    huge.data.num_query = usize::MAX;
+    let plan = MemoryPlan::of(&huge);
+    assert_eq!(plan.index, usize::MAX);
+    assert_eq!(plan.hashes, usize::MAX);
+    assert_eq!(plan.total(), usize::MAX);
+    huge.lsh.storage = Storage::Frozen;
+    assert_eq!(MemoryPlan::of(&huge).index, usize::MAX);
+  }
+
+  #[test]
+  fn test_sparse_estimate() {
+    // Uniformly spread items should be estimated within a factor of two.
+    let mut config = config();
+    config.lsh.storage = Storage::Sparse;
+    let mut lsh =
+      LSH::with_storage(16, 12, 16, Storage::Sparse, &mut StdRng::seed_from_u64(2)).unwrap();
+    let hashes: Vec<HashType> = (0..10_000 * 16)
+      .map(|i: u32| i.wrapping_mul(2_654_435_761) >> 20)
+      .collect();
+    lsh.insert_range(0, 10_000, &hashes);
+
+    let estimate = MemoryPlan::of(&config).index as f64;
+    let actual = lsh.memory_bytes() as f64;
+    assert!(estimate / actual > 0.5 && estimate / actual < 2.0);
+  }
+
+  #[test]
+  fn test_suggest() {
+    let config = config();
+    let plan = MemoryPlan::of(&config);
+    assert!(suggest(&config, plan.total()).is_empty());
+
+    let budget = plan.total() - plan.index / 2;
+    let suggestions = suggest(&config, budget);
+    let keys: Vec<&str> = suggestions.iter().map(|s| s.key).collect();
+    assert_eq!(
+      keys,
+      vec!["lsh.range_pow", "lsh.reservoir_size", "lsh.tables"]
+    );
+    for s in &suggestions {
+      assert!(s.plan.total() <= budget);
+    }
+    assert_eq!(suggestions[0].value, 11);
+    assert_eq!(suggestions[1].value, 7);
+    assert_eq!(suggestions[2].value, 9);
+
+    // Nothing helps when the data alone is over budget.
+    assert!(suggest(&config, plan.data).is_empty());
+  }
+}
@@ -75,6 +75,18 @@ pub fn partition(total_len: usize, num: usize) -> Vec<usize> {
 }
 
 pub fn read_data_svm(filename: &str, num_lines: usize, avg_dim: usize, skip: usize) -> SVMData {
+  let (data, _) = read_data_svm_labeled(filename, num_lines, avg_dim, skip);
+  return data;
+}
+
+/// Same as `read_data_svm` but also returns the label column, which is the first field of each
+/// line, so that it can be stored with the index and used to filter query results.
+pub fn read_data_svm_labeled(
+  filename: &str,
+  num_lines: usize,
+  avg_dim: usize,
+  skip: usize,
+) -> (SVMData, Vec<String>) {
   let input = File::open(filename).expect("File should open");
 
   let reader = BufReader::new(input);
@@ -82,6 +94,7 @@ pub fn read_data_svm(filename: &str, num_lines: usize, avg_dim: usize, skip: usi
   let mut markers: Vec<usize> = Vec::with_capacity(num_lines + 1);
   let mut indices: Vec<HashType> = Vec::with_capacity(num_lines * avg_dim);
   let mut values: Vec<f32> = Vec::with_capacity(num_lines * avg_dim);
+  let mut labels: Vec<String> = Vec::with_capacity(num_lines);
   let mut lines_read = 0;
 
   markers.push(0);
@@ -89,7 +102,9 @@ pub fn read_data_svm(filename: &str, num_lines: usize, avg_dim: usize, skip: usi
   for line in reader.lines().skip(skip) {
     match line {
       Ok(s) => {
-        for pair in s.split(' ').skip(1) {
+        let mut fields = s.split(' ');
+        labels.push(fields.next().unwrap_or("").to_string());
+        for pair in fields {
           let i = pair.find(':').expect("Pair should have ':'");
           indices.push(pair[..i].parse::<HashType>().expect("Should be integer"));
           values.push(pair[i + 1..].parse::<f32>().expect("Should be float"));
@@ -105,12 +120,13 @@ pub fn read_data_svm(filename: &str, num_lines: usize, avg_dim: usize, skip: usi
     }
   }
 
-  return SVMData {
+  let data = SVMData {
     markers,
     indices,
     values,
     len: num_lines,
   };
+  return (data, labels);
 }
 
 pub fn read_data_svm_partitioned(
@@ -287,4 +303,20 @@ mod tests {
 
     std::fs::remove_file(filename).expect("Shoudl be able to delete file after test");
   }
+
+  #[test]
+  fn test_read_svm_labeled() {
+    let filename = "./temp_reader_labeled_test_file";
+
+    create_and_write_data(filename);
+
+    let (data, labels) = read_data_svm_labeled(filename, 3, 3, 1);
+
+    assert_eq!(labels, vec!["0", "1", "1"]);
+    assert_eq!(data.len(), 3);
+    assert_eq!(data.markers, vec![0, 4, 8, 9]);
+    assert_eq!(data.indices[4..], [88, 91, 120, 18223, 4]);
+
+    std::fs::remove_file(filename).expect("Should be able to delete file after test");
+  }
 }
@@ -0,0 +1,303 @@
+use crate::lsh::LSH;
+
+use std::fmt;
+
+/// How full the buckets of one table of an `LSH` are.
+pub struct TableStats {
+  /// `histogram[n]` is the number of buckets holding `n` items, for `n` up to `reservoir_size`.
+  pub histogram: Vec<usize>,
+  /// The number of buckets more items reached than they can hold.
+  pub overflowed: usize,
+  /// The number of items that reached an overflowed bucket after it was full, and so were either
+  /// dropped or evicted another item. Buckets that items were removed from only count what they
+  /// still hold, so their earlier overflow is not included.
+  pub overflow: u64,
+  /// The number of items that reached any bucket, including the ones that were not kept, except
+  /// those lost from the counts of buckets items were removed from.
+  pub load: u64,
+  /// The largest number of items that reached a single bucket.
+  pub max_load: u32,
+}
+
+impl TableStats {
+  pub fn buckets(&self) -> usize {
+    self.histogram.iter().sum()
+  }
+
+  pub fn empty(&self) -> usize {
+    self.histogram[0]
+  }
+
+  /// The average number of items that reached a bucket, counting empty buckets.
+  pub fn mean_load(&self) -> f64 {
+    self.load as f64 / self.buckets() as f64
+  }
+}
+
+/// Bucket occupancy and memory use of an `LSH`, for choosing `range_pow` and `reservoir_size`.
+/// Many empty buckets mean `range_pow` could be lowered, and many overflowed buckets mean either
+/// could be raised. Unlike the `Display` implementation of `LSH` it only reads bucket counts, so
+/// it is cheap enough for full size indexes.
+pub struct Stats {
+  pub tables: Vec<TableStats>,
+  pub reservoir_size: usize,
+  /// The approximate number of bytes of memory taken by the buckets.
+  pub memory_bytes: usize,
+}
+
+impl Stats {
+  pub fn of(lsh: &LSH) -> Self {
+    let reservoir_size = lsh.reservoir_size();
+    let tables = (0..lsh.tables())
+      .map(|t| {
+        let mut table = TableStats {
+          histogram: vec![0; reservoir_size + 1],
+          overflowed: 0,
+          overflow: 0,
+          load: 0,
+          max_load: 0,
+        };
+        let mut occupied = 0;
+        lsh.for_each_count(t, |count| {
+          occupied += 1;
+          table.histogram[std::cmp::min(count as usize, reservoir_size)] += 1;
+          if count as usize > reservoir_size {
+            table.overflowed += 1;
+            table.overflow += (count as usize - reservoir_size) as u64;
+          }
+          table.load += count as u64;
+          table.max_load = std::cmp::max(table.max_load, count);
+        });
+        table.histogram[0] = lsh.rows() - occupied;
+        table
+      })
+      .collect();
+
+    return Stats {
+      tables,
+      reservoir_size,
+      memory_bytes: lsh.memory_bytes(),
+    };
+  }
+
+  pub fn buckets(&self) -> usize {
+    self.tables.iter().map(|t| t.buckets()).sum()
+  }
+
+  pub fn empty_fraction(&self) -> f64 {
+    let empty: usize = self.tables.iter().map(|t| t.empty()).sum();
+    empty as f64 / self.buckets() as f64
+  }
+
+  pub fn overflowed(&self) -> usize {
+    self.tables.iter().map(|t| t.overflowed).sum()
+  }
+
+  pub fn overflow(&self) -> u64 {
+    self.tables.iter().map(|t| t.overflow).sum()
+  }
+
+  pub fn mean_load(&self) -> f64 {
+    let load: u64 = self.tables.iter().map(|t| t.load).sum();
+    load as f64 / self.buckets() as f64
+  }
+
+  pub fn max_load(&self) -> u32 {
+    self.tables.iter().map(|t| t.max_load).max().unwrap_or(0)
+  }
+
+  /// Groups the histogram columns into empty buckets, up to four ranges of partly full buckets
+  /// and full buckets, as inclusive ranges of item counts.
+  fn columns(&self) -> Vec<(usize, usize)> {
+    let res = self.reservoir_size;
+    let mut columns = vec![(0, 0)];
+    let partial = res - 1;
+    let num = std::cmp::min(partial, 4);
+    let mut start = 1;
+    for i in 0..num {
+      let end = partial * (i + 1) / num;
+      columns.push((start, end));
+      start = end + 1;
+    }
+    columns.push((res, res));
+    return columns;
+  }
+}
+
+/// Formats a number of bytes with a binary unit, e.g. `1.50 GB`.
+pub fn format_bytes(bytes: usize) -> String {
+  let units = ["B", "KB", "MB", "GB", "TB"];
+  let mut value = bytes as f64;
+  let mut unit = 0;
+  while value >= 1024.0 && unit + 1 < units.len() {
+    value /= 1024.0;
+    unit += 1;
+  }
+  if unit == 0 {
+    return format!("{} B", bytes);
+  }
+  return format!("{:.2} {}", value, units[unit]);
+}
+
+/// A short summary followed by one line per table with its histogram, grouped into a few columns
+/// of bucket sizes.
+impl fmt::Display for Stats {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let buckets = self.tables.first().map_or(0, |t| t.buckets());
+    writeln!(
+      f,
+      "{} tables of {} buckets with reservoir_size {}, taking {}",
+      self.tables.len(),
+      buckets,
+      self.reservoir_size,
+      format_bytes(self.memory_bytes)
+    )?;
+    writeln!(f, "Empty buckets: {:.2}%", 100.0 * self.empty_fraction())?;
+    writeln!(
+      f,
+      "Overflowed buckets: {} ({:.2}%), reached by {} items more than they hold",
+      self.overflowed(),
+      100.0 * self.overflowed() as f64 / self.buckets() as f64,
+      self.overflow()
+    )?;
+    writeln!(
+      f,
+      "Load: mean {:.3}, max {}",
+      self.mean_load(),
+      self.max_load()
+    )?;
+    writeln!(f)?;
+
+    let columns = self.columns();
+    write!(f, "table")?;
+    for &(start, end) in &columns {
+      let name = match (start, end) {
+        (0, 0) => String::from("empty"),
+        _ if start == self.reservoir_size => String::from("full"),
+        _ if start == end => start.to_string(),
+        _ => format!("{}-{}", start, end),
+      };
+      write!(f, " {:>9}", name)?;
+    }
+    writeln!(
+      f,
+      " {:>10} {:>10} {:>7} {:>6}",
+      "overflowed", "overflow", "mean", "max"
+    )?;
+
+    for (t, table) in self.tables.iter().enumerate() {
+      write!(f, "{:>5}", t)?;
+      for &(start, end) in &columns {
+        let count: usize = table.histogram[start..end + 1].iter().sum();
+        write!(f, " {:>9}", count)?;
+      }
+      writeln!(
+        f,
+        " {:>10} {:>10} {:>7.3} {:>6}",
+        table.overflowed,
+        table.overflow,
+        table.mean_load(),
+        table.max_load
+      )?;
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::lsh::{EvictionPolicy, Storage};
+  use rand::rngs::StdRng;
+  use rand::SeedableRng;
+
+  #[test]
+  fn test_stats() {
+    // Table 0 puts all five items in bucket 0, table 1 spreads them over every bucket.
+    let ids: Vec<u32> = (0..5).collect();
+    let hashes: Vec<u32> = ids.iter().flat_map(|&id| vec![0, id % 4]).collect();
+
+    for storage in vec![Storage::Dense, Storage::Sparse, Storage::Frozen].into_iter() {
+      let build = if storage == Storage::Frozen {
+        Storage::Sparse
+      } else {
+        storage
+      };
+      let mut lsh = LSH::with_storage(2, 2, 2, build, &mut StdRng::seed_from_u64(1)).unwrap();
+      lsh.insert(&ids, &hashes);
+      if storage == Storage::Frozen {
+        lsh.freeze();
+      }
+
+      let stats = Stats::of(&lsh);
+      assert_eq!(stats.tables[0].histogram, vec![3, 0, 1]);
+      assert_eq!(stats.tables[0].overflowed, 1);
+      assert_eq!(stats.tables[0].overflow, 3);
+      assert_eq!(stats.tables[0].max_load, 5);
+      assert_eq!(stats.tables[1].histogram, vec![0, 3, 1]);
+      assert_eq!(stats.tables[1].overflowed, 0);
+      assert_eq!(stats.tables[1].max_load, 2);
+
+      assert_eq!(stats.buckets(), 8);
+      assert_eq!(stats.empty_fraction(), 3.0 / 8.0);
+      assert_eq!(stats.overflowed(), 1);
+      assert_eq!(stats.overflow(), 3);
+      assert_eq!(stats.mean_load(), 10.0 / 8.0);
+      assert_eq!(stats.max_load(), 5);
+      assert_eq!(stats.memory_bytes, lsh.memory_bytes());
+
+      let report = stats.to_string();
+      assert_eq!(report.lines().count(), 6 + 2);
+      assert!(report
+        .lines()
+        .last()
+        .unwrap()
+        .starts_with("    1         0         3         1"));
+    }
+  }
+
+  #[test]
+  fn test_stats_after_remove() {
+    // Five items reach bucket 0 of the single table, which holds two of them.
+    let hashes = vec![0; 5];
+    let mut lsh = LSH::with_storage(1, 2, 2, Storage::Dense, &mut StdRng::seed_from_u64(1))
+      .unwrap()
+      .with_eviction(EvictionPolicy::KeepOldest);
+    lsh.insert_range(0, 5, &hashes);
+    assert_eq!(Stats::of(&lsh).overflow(), 3);
+
+    // Removing an item resets the count to the one item left, so the overflow is forgotten and
+    // the next item takes the freed slot even though the bucket already dropped three items.
+    assert_eq!(lsh.remove(0, &[0]), 1);
+    let stats = Stats::of(&lsh);
+    assert_eq!(stats.overflow(), 0);
+    assert_eq!(stats.tables[0].load, 1);
+    assert_eq!(stats.tables[0].histogram, vec![3, 1, 0]);
+
+    lsh.insert_range(5, 1, &hashes);
+    let stats = Stats::of(&lsh);
+    assert_eq!(stats.tables[0].load, 2);
+    assert_eq!(stats.tables[0].histogram, vec![3, 0, 1]);
+    assert_eq!(lsh.query(&[0], 2).ids(0).collect::<Vec<_>>(), vec![1, 5]);
+  }
+
+  #[test]
+  fn test_columns_and_bytes() {
+    let stats = |reservoir_size| Stats {
+      tables: Vec::new(),
+      reservoir_size,
+      memory_bytes: 0,
+    };
+    assert_eq!(stats(1).columns(), vec![(0, 0), (1, 1)]);
+    assert_eq!(stats(3).columns(), vec![(0, 0), (1, 1), (2, 2), (3, 3)]);
+    assert_eq!(
+      stats(32).columns(),
+      vec![(0, 0), (1, 7), (8, 15), (16, 23), (24, 31), (32, 32)]
+    );
+
+    assert_eq!(format_bytes(512), "512 B");
+    assert_eq!(format_bytes(1536), "1.50 KB");
+    assert_eq!(format_bytes(3 << 30), "3.00 GB");
+  }
+}
@@ -0,0 +1,316 @@
+use crate::lsh::{HashType, IDType};
+use crate::persist::{invalid_data, Decoder, Encoder};
+use crate::reader::SVMData;
+
+use std::io;
+use std::io::{Read, Write};
+
+const STORE_MAGIC: &[u8; 8] = b"FLASHVEC";
+const STORE_VERSION: u32 = 1;
+
+/// Marks an ID without a stored vector in `VectorStore::rows`.
+const NO_ROW: u32 = u32::MAX;
+
+/// Owns the original sparse vectors of the items in an index so they can be fetched by ID. The
+/// vectors are kept as rows of an `SVMData`, and `rows[id]` gives the row holding the vector of
+/// `id`. Replacing a vector appends a new row, leaving the old one unused until the store is
+/// saved and loaded again.
+pub struct VectorStore {
+  data: SVMData,
+  rows: Vec<u32>,
+  live: usize,
+}
+
+impl Default for VectorStore {
+  fn default() -> Self {
+    VectorStore::new()
+  }
+}
+
+impl VectorStore {
+  pub fn new() -> Self {
+    VectorStore {
+      data: SVMData {
+        markers: vec![0],
+        indices: Vec::new(),
+        values: Vec::new(),
+        len: 0,
+      },
+      rows: Vec::new(),
+      live: 0,
+    }
+  }
+
+  /// Stores the vectors in `data` under the IDs `first_id, first_id + 1, ...`, replacing any
+  /// vectors already stored under them.
+  pub fn insert_range(&mut self, first_id: IDType, data: &SVMData) {
+    for i in 0..data.len() {
+      self.insert(first_id + i as IDType, data, i);
+    }
+  }
+
+  /// Stores row `row` of `data` under `id`, replacing any vector already stored under it.
+  pub fn insert(&mut self, id: IDType, data: &SVMData, row: usize) {
+    let (start, end) = (data.markers[row], data.markers[row + 1]);
+    self
+      .data
+      .indices
+      .extend_from_slice(&data.indices[start..end]);
+    self.data.values.extend_from_slice(&data.values[start..end]);
+    self.data.markers.push(self.data.indices.len());
+
+    let id = id as usize;
+    if id >= self.rows.len() {
+      self.rows.resize(id + 1, NO_ROW);
+    }
+    if self.rows[id] == NO_ROW {
+      self.live += 1;
+    }
+    self.rows[id] = self.data.len as u32;
+    self.data.len += 1;
+  }
+
+  /// Forgets the vector stored under `id`. Returns whether there was one.
+  pub fn remove(&mut self, id: IDType) -> bool {
+    match self.rows.get_mut(id as usize) {
+      Some(row) if *row != NO_ROW => {
+        *row = NO_ROW;
+        self.live -= 1;
+        true
+      }
+      _ => false,
+    }
+  }
+
+  /// The row of `data()` holding the vector stored under `id`.
+  pub fn row(&self, id: IDType) -> Option<usize> {
+    match self.rows.get(id as usize) {
+      Some(&row) if row != NO_ROW => Some(row as usize),
+      _ => None,
+    }
+  }
+
+  /// The stored vectors, see `row`.
+  pub fn data(&self) -> &SVMData {
+    &self.data
+  }
+
+  /// Returns the `(index, value)` pairs of the vector stored under `id`.
+  pub fn get(&self, id: IDType) -> Option<impl Iterator<Item = (HashType, f32)> + '_> {
+    let row = self.row(id)?;
+    let (start, end) = (self.data.markers[row], self.data.markers[row + 1]);
+    Some(
+      self.data.indices[start..end]
+        .iter()
+        .copied()
+        .zip(self.data.values[start..end].iter().copied()),
+    )
+  }
+
+  /// Copies the vectors stored under `ids` into a new `SVMData`, in the same order. Returns the
+  /// first ID without a stored vector if there is one.
+  pub fn fetch(&self, ids: &[IDType]) -> Result<SVMData, IDType> {
+    let mut fetched = SVMData {
+      markers: Vec::with_capacity(ids.len() + 1),
+      indices: Vec::new(),
+      values: Vec::new(),
+      len: ids.len(),
+    };
+    fetched.markers.push(0);
+    for &id in ids {
+      let row = self.row(id).ok_or(id)?;
+      let (start, end) = (self.data.markers[row], self.data.markers[row + 1]);
+      fetched
+        .indices
+        .extend_from_slice(&self.data.indices[start..end]);
+      fetched
+        .values
+        .extend_from_slice(&self.data.values[start..end]);
+      fetched.markers.push(fetched.indices.len());
+    }
+    Ok(fetched)
+  }
+
+  /// An ID above every ID with a stored vector.
+  pub fn next_id(&self) -> IDType {
+    self.rows.len() as IDType
+  }
+
+  /// The number of stored vectors.
+  pub fn len(&self) -> usize {
+    self.live
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.live == 0
+  }
+
+  /// The approximate number of bytes of memory taken by the stored vectors, including rows that
+  /// were replaced or removed.
+  pub fn memory_bytes(&self) -> usize {
+    self.data.markers.capacity() * std::mem::size_of::<usize>()
+      + self.data.indices.capacity() * std::mem::size_of::<HashType>()
+      + self.data.values.capacity() * std::mem::size_of::<f32>()
+      + self.rows.capacity() * std::mem::size_of::<u32>()
+  }
+
+  /// Writes the stored vectors in ID order, leaving out rows that were replaced or removed.
+  pub fn write_to<W: Write>(&self, e: &mut Encoder<W>) -> io::Result<()> {
+    let ids: Vec<IDType> = (0..self.rows.len() as IDType)
+      .filter(|&id| self.row(id).is_some())
+      .collect();
+    let mut lens = Vec::with_capacity(ids.len());
+    let mut indices = Vec::new();
+    let mut values = Vec::new();
+    for &id in &ids {
+      let row = self.row(id).unwrap();
+      let (start, end) = (self.data.markers[row], self.data.markers[row + 1]);
+      lens.push((end - start) as u32);
+      indices.extend_from_slice(&self.data.indices[start..end]);
+      values.extend(self.data.values[start..end].iter().map(|v| v.to_bits()));
+    }
+
+    e.write_header(STORE_MAGIC, STORE_VERSION)?;
+    e.write_u64(ids.len() as u64)?;
+    e.write_u64(indices.len() as u64)?;
+    e.write_checksum()?;
+
+    e.write_u32_slice(&ids)?;
+    e.write_u32_slice(&lens)?;
+    e.write_checksum()?;
+
+    e.write_u32_slice(&indices)?;
+    e.write_u32_slice(&values)?;
+    e.write_checksum()
+  }
+
+  pub fn read_from<R: Read>(d: &mut Decoder<R>) -> io::Result<VectorStore> {
+    d.read_header(STORE_MAGIC, STORE_VERSION)?;
+    let num_vectors = d.read_usize()?;
+    let num_entries = d.read_usize()?;
+    d.verify_checksum("vector store header")?;
+
+    if num_vectors > IDType::MAX as usize {
+      return Err(invalid_data(format!(
+        "vector store holds {} vectors, more than there are ids",
+        num_vectors
+      )));
+    }
+
+    let ids = d.read_u32_array(num_vectors)?;
+    let lens = d.read_u32_array(num_vectors)?;
+    d.verify_checksum("vector store ids")?;
+
+    let total: u64 = lens.iter().map(|&l| l as u64).sum();
+    if total != num_entries as u64 {
+      return Err(invalid_data(format!(
+        "vector store lengths add up to {} but it holds {} entries",
+        total, num_entries
+      )));
+    }
+    if ids.windows(2).any(|w| w[0] >= w[1]) || ids.last().is_some_and(|&id| id == NO_ROW) {
+      return Err(invalid_data(String::from(
+        "vector store ids are not strictly increasing",
+      )));
+    }
+
+    let indices = d.read_u32_array(num_entries)?;
+    let values = d.read_u32_array(num_entries)?;
+    d.verify_checksum("vector store data")?;
+
+    let mut markers = Vec::with_capacity(num_vectors + 1);
+    markers.push(0);
+    for &len in lens.iter() {
+      markers.push(markers[markers.len() - 1] + len as usize);
+    }
+
+    let mut rows = vec![NO_ROW; ids.last().map_or(0, |&id| id as usize + 1)];
+    for (row, &id) in ids.iter().enumerate() {
+      rows[id as usize] = row as u32;
+    }
+
+    Ok(VectorStore {
+      data: SVMData {
+        markers,
+        indices: indices.to_vec(),
+        values: values.iter().map(|&v| f32::from_bits(v)).collect(),
+        len: num_vectors,
+      },
+      rows,
+      live: num_vectors,
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn test_data() -> SVMData {
+    SVMData {
+      markers: vec![0, 2, 2, 5],
+      indices: vec![3, 7, 1, 2, 9],
+      values: vec![0.5, -1.0, 2.0, 0.25, 8.0],
+      len: 3,
+    }
+  }
+
+  #[test]
+  fn test_insert_fetch() {
+    let mut store = VectorStore::new();
+    store.insert_range(10, &test_data());
+
+    assert_eq!(store.len(), 3);
+    assert!(store.get(9).is_none());
+    assert_eq!(
+      store.get(10).unwrap().collect::<Vec<_>>(),
+      vec![(3, 0.5), (7, -1.0)]
+    );
+    assert_eq!(store.get(11).unwrap().count(), 0);
+
+    let fetched = store.fetch(&[12, 10]).unwrap();
+    assert_eq!(fetched.len(), 2);
+    assert_eq!(fetched.markers, vec![0, 3, 5]);
+    assert_eq!(fetched.indices, vec![1, 2, 9, 3, 7]);
+    assert_eq!(fetched.values, vec![2.0, 0.25, 8.0, 0.5, -1.0]);
+    assert_eq!(store.fetch(&[10, 13, 14]).err(), Some(13));
+
+    store.insert(10, &test_data(), 2);
+    assert!(store.remove(11));
+    assert!(!store.remove(11));
+    assert_eq!(store.len(), 2);
+    assert_eq!(
+      store.get(10).unwrap().collect::<Vec<_>>(),
+      vec![(1, 2.0), (2, 0.25), (9, 8.0)]
+    );
+    assert!(store.row(11).is_none());
+  }
+
+  #[test]
+  fn test_write_read() {
+    let mut store = VectorStore::new();
+    store.insert_range(4, &test_data());
+    store.insert(5, &test_data(), 0);
+    store.remove(4);
+
+    let mut e = Encoder::new(Vec::new());
+    store.write_to(&mut e).unwrap();
+    let bytes = e.into_inner();
+    let loaded = VectorStore::read_from(&mut Decoder::new(&bytes[..])).unwrap();
+
+    assert_eq!(loaded.len(), 2);
+    assert_eq!(loaded.data().len(), 2);
+    assert!(loaded.row(4).is_none());
+    for id in 5..7 {
+      assert_eq!(
+        loaded.get(id).unwrap().collect::<Vec<_>>(),
+        store.get(id).unwrap().collect::<Vec<_>>()
+      );
+    }
+
+    let mut corrupt = bytes.clone();
+    let last = corrupt.len() - 9;
+    corrupt[last] ^= 1;
+    assert!(VectorStore::read_from(&mut Decoder::new(&corrupt[..])).is_err());
+  }
+}